@@ -1,9 +1,10 @@
-use crate::share::TransExt;
+use crate::share::{SessionStore, SharedState, TransExt, SESSION_COOKIE};
 use rustdb::{
-    c_int, c_value, check_types, standard_builtins, Block, BuiltinMap, CExp, CExpPtr, CompileFunc,
-    DataKind, EvalEnv, Expr, GenTransaction, Value,
+    c_float, c_int, c_value, check_types, standard_builtins, Block, BuiltinMap, CExp, CExpPtr,
+    CompileFunc, DataKind, EvalEnv, Expr, GenTransaction, ObjRef, Value, DB,
 };
 use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
 
 /// Get BuiltinMap
 pub fn get_bmap() -> BuiltinMap {
@@ -18,6 +19,8 @@ pub fn get_bmap() -> BuiltinMap {
         ("SETDOS", DataKind::Int, CompileFunc::Int(c_setdos)),
         ("TRANSWAIT", DataKind::Int, CompileFunc::Int(c_trans_wait)),
         ("TRANSFLUSH", DataKind::Int, CompileFunc::Int(c_trans_flush)),
+        ("REPSYNC", DataKind::Int, CompileFunc::Int(c_rep_sync)),
+        ("BULK", DataKind::Int, CompileFunc::Int(c_bulk)),
         ("TOPDF", DataKind::Int, CompileFunc::Int(c_topdf)),
         ("BINPACK", DataKind::Binary, CompileFunc::Value(c_binpack)),
         (
@@ -34,6 +37,65 @@ pub fn get_bmap() -> BuiltinMap {
         ("NOLOG", DataKind::Int, CompileFunc::Int(c_nolog)),
         ("ADLER", DataKind::Int, CompileFunc::Int(c_adler)),
         ("DOLOG", DataKind::Int, CompileFunc::Int(c_dolog)),
+        (
+            "LEVENSHTEIN",
+            DataKind::Int,
+            CompileFunc::Int(c_levenshtein),
+        ),
+        ("SIMILARITY", DataKind::Int, CompileFunc::Int(c_similarity)),
+        ("SOUNDEX", DataKind::String, CompileFunc::Value(c_soundex)),
+        ("PAGESIZE", DataKind::Int, CompileFunc::Int(c_pagesize)),
+        ("PAGEOFFSET", DataKind::Int, CompileFunc::Int(c_pageoffset)),
+        ("PAGECOUNT", DataKind::Int, CompileFunc::Int(c_pagecount)),
+        ("REQSTART", DataKind::Int, CompileFunc::Int(c_reqstart)),
+        ("ELAPSEDMS", DataKind::Int, CompileFunc::Int(c_elapsedms)),
+        (
+            "NUMFORMAT",
+            DataKind::String,
+            CompileFunc::Value(c_numformat),
+        ),
+        (
+            "CURRENCYFORMAT",
+            DataKind::String,
+            CompileFunc::Value(c_currencyformat),
+        ),
+        ("NUMPARSE", DataKind::Float, CompileFunc::Float(c_numparse)),
+        ("MARKDOWN", DataKind::String, CompileFunc::Value(c_markdown)),
+        (
+            "MARKDOWNTRUSTED",
+            DataKind::String,
+            CompileFunc::Value(c_markdown_trusted),
+        ),
+        (
+            "SIGNTOKEN",
+            DataKind::String,
+            CompileFunc::Value(c_signtoken),
+        ),
+        (
+            "VERIFYTOKEN",
+            DataKind::String,
+            CompileFunc::Value(c_verifytoken),
+        ),
+        ("CTEQ", DataKind::Int, CompileFunc::Int(c_cteq)),
+        ("GEOIP", DataKind::String, CompileFunc::Value(c_geoip)),
+        ("ENQUEUE", DataKind::Int, CompileFunc::Int(c_enqueue)),
+        ("SENDMAILAT", DataKind::Int, CompileFunc::Int(c_sendmail_at)),
+        ("SETTRAILER", DataKind::Int, CompileFunc::Int(c_settrailer)),
+        ("CACHEGET", DataKind::String, CompileFunc::Value(c_cacheget)),
+        ("CACHESET", DataKind::Int, CompileFunc::Int(c_cacheset)),
+        ("CACHECLEAR", DataKind::Int, CompileFunc::Int(c_cacheclear)),
+        ("SESSION", DataKind::String, CompileFunc::Value(c_session)),
+        (
+            "SESSIONGET",
+            DataKind::String,
+            CompileFunc::Value(c_sessionget),
+        ),
+        ("SESSIONSET", DataKind::Int, CompileFunc::Int(c_sessionset)),
+        (
+            "SESSIONDESTROY",
+            DataKind::Int,
+            CompileFunc::Int(c_sessiondestroy),
+        ),
     ];
     for (name, typ, cf) in list {
         bmap.insert(name.to_string(), (typ, cf));
@@ -123,7 +185,7 @@ impl CExp<i64> for SetDos {
         }
         let mut ext = ee.tr.get_extension();
         if let Some(ext) = ext.downcast_mut::<TransExt>() {
-            ext.uid = uid.clone();
+            ext.ctx.uid = uid.clone();
             if !ext.set_dos(uid, to) {
                 result = 0;
             }
@@ -190,6 +252,54 @@ impl CExp<i64> for TransFlush {
     }
 }
 
+/// Compile call to REPSYNC.
+fn c_rep_sync(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    Box::new(RepSync {})
+}
+
+/// Compiled call to REPSYNC
+struct RepSync {}
+impl CExp<i64> for RepSync {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.rep_sync = true;
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Compile call to BULK.
+fn c_bulk(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int]);
+    let secs = c_int(b, &mut args[0]);
+    Box::new(Bulk { secs })
+}
+
+/// Compiled call to BULK
+struct Bulk {
+    secs: CExpPtr<i64>,
+}
+impl CExp<i64> for Bulk {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let secs = self.secs.eval(ee, d).max(0);
+        let until = ee.tr.global(0) + secs * 1_000_000;
+        let mut result = 0;
+        let ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            if let Some(ss) = &ext.ss {
+                ss.bulk_until
+                    .store(until, std::sync::atomic::Ordering::Relaxed);
+                result = 1;
+            }
+        }
+        ee.tr.set_extension(ext);
+        result
+    }
+}
+
 /// Compile call to TOPDF
 fn c_topdf(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
     check_types(b, args, &[]);
@@ -347,3 +457,1202 @@ impl CExp<i64> for Adler {
         flate3::adler32(bytes.bina()) as i64
     }
 }
+
+/// Maximum number of chars compared by LEVENSHTEIN and SIMILARITY ( excess chars are ignored ).
+const FUZZY_MAX: usize = 256;
+
+/// Edit distance ( insertions, deletions, substitutions of chars ) between first FUZZY_MAX chars of a and b.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().take(FUZZY_MAX).collect();
+    let b: Vec<char> = b.chars().take(FUZZY_MAX).collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let sub = diag + if ca == cb { 0 } else { 1 };
+            diag = row[j + 1];
+            row[j + 1] = sub.min(row[j] + 1).min(diag + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Compile call to LEVENSHTEIN.
+fn c_levenshtein(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::String, DataKind::String]);
+    let x = c_value(b, &mut args[0]);
+    let y = c_value(b, &mut args[1]);
+    Box::new(Levenshtein { x, y })
+}
+
+/// Compiled call to LEVENSHTEIN
+struct Levenshtein {
+    x: CExpPtr<Value>,
+    y: CExpPtr<Value>,
+}
+impl CExp<i64> for Levenshtein {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let x = self.x.eval(ee, d).str();
+        let y = self.y.eval(ee, d).str();
+        levenshtein(&x, &y) as i64
+    }
+}
+
+/// Default page size ( used if size is less than 1 ).
+const PAGE_SIZE: i64 = 20;
+/// Maximum page size.
+const MAX_PAGE_SIZE: i64 = 1000;
+/// Maximum offset returned by PAGEOFFSET.
+const MAX_PAGE_OFFSET: i64 = 1_000_000;
+
+/// Get page size clamped to 1..MAX_PAGE_SIZE.
+fn page_size(size: i64) -> i64 {
+    if size < 1 {
+        PAGE_SIZE
+    } else {
+        size.min(MAX_PAGE_SIZE)
+    }
+}
+
+/// Compile call to PAGESIZE.
+fn c_pagesize(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int]);
+    let size = c_int(b, &mut args[0]);
+    Box::new(PageSize { size })
+}
+
+/// Compiled call to PAGESIZE
+struct PageSize {
+    size: CExpPtr<i64>,
+}
+impl CExp<i64> for PageSize {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        page_size(self.size.eval(ee, d))
+    }
+}
+
+/// Compile call to PAGEOFFSET.
+fn c_pageoffset(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int, DataKind::Int]);
+    let page = c_int(b, &mut args[0]);
+    let size = c_int(b, &mut args[1]);
+    Box::new(PageOffset { page, size })
+}
+
+/// Compiled call to PAGEOFFSET
+struct PageOffset {
+    page: CExpPtr<i64>,
+    size: CExpPtr<i64>,
+}
+impl CExp<i64> for PageOffset {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let page = self.page.eval(ee, d).clamp(1, MAX_PAGE_OFFSET);
+        let size = page_size(self.size.eval(ee, d));
+        // Offset is that of the last whole page which starts at or below MAX_PAGE_OFFSET.
+        ((page - 1) * size).min(MAX_PAGE_OFFSET / size * size)
+    }
+}
+
+/// Compile call to PAGECOUNT.
+fn c_pagecount(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int, DataKind::Int]);
+    let count = c_int(b, &mut args[0]);
+    let size = c_int(b, &mut args[1]);
+    Box::new(PageCount { count, size })
+}
+
+/// Compiled call to PAGECOUNT
+struct PageCount {
+    count: CExpPtr<i64>,
+    size: CExpPtr<i64>,
+}
+impl CExp<i64> for PageCount {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let count = self.count.eval(ee, d).max(0);
+        let size = page_size(self.size.eval(ee, d));
+        (count.saturating_add(size - 1) / size).max(1)
+    }
+}
+
+/// Compile call to SIMILARITY.
+fn c_similarity(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::String, DataKind::String]);
+    let x = c_value(b, &mut args[0]);
+    let y = c_value(b, &mut args[1]);
+    Box::new(Similarity { x, y })
+}
+
+/// Compiled call to SIMILARITY
+struct Similarity {
+    x: CExpPtr<Value>,
+    y: CExpPtr<Value>,
+}
+impl CExp<i64> for Similarity {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let x = self.x.eval(ee, d).str();
+        let y = self.y.eval(ee, d).str();
+        similarity(&x, &y)
+    }
+}
+
+/// Similarity of a and b as a percentage, based on the edit distance relative to the length of the longer string.
+fn similarity(a: &str, b: &str) -> i64 {
+    let n = a.chars().count().max(b.chars().count()).min(FUZZY_MAX);
+    if n == 0 {
+        return 100;
+    }
+    (100 - 100 * levenshtein(a, b) / n) as i64
+}
+
+/// Compile call to SOUNDEX.
+fn c_soundex(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let s = c_value(b, &mut args[0]);
+    Box::new(Soundex { s })
+}
+
+/// Compiled call to SOUNDEX
+struct Soundex {
+    s: CExpPtr<Value>,
+}
+impl CExp<Value> for Soundex {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let s = self.s.eval(ee, d).str();
+        Value::String(Rc::new(soundex(&s)))
+    }
+}
+
+/// American Soundex code ( letter followed by 3 digits ), empty if s has no ASCII letters.
+fn soundex(s: &str) -> String {
+    fn code(c: u8) -> u8 {
+        match c {
+            b'B' | b'F' | b'P' | b'V' => b'1',
+            b'C' | b'G' | b'J' | b'K' | b'Q' | b'S' | b'X' | b'Z' => b'2',
+            b'D' | b'T' => b'3',
+            b'L' => b'4',
+            b'M' | b'N' => b'5',
+            b'R' => b'6',
+            b'H' | b'W' => b'H',
+            _ => b'0', // Vowels ( and Y ).
+        }
+    }
+    let mut letters = s
+        .bytes()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_uppercase());
+    let Some(first) = letters.next() else {
+        return String::new();
+    };
+    let mut result = vec![first];
+    let mut last = code(first);
+    for c in letters {
+        let x = code(c);
+        if x == b'H' {
+            // H and W do not separate letters with the same code.
+            continue;
+        }
+        if x != b'0' && x != last {
+            result.push(x);
+            if result.len() == 4 {
+                break;
+            }
+        }
+        last = x;
+    }
+    result.resize(4, b'0');
+    String::from_utf8(result).unwrap()
+}
+
+/// Compile call to REQSTART.
+fn c_reqstart(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    Box::new(ReqStart {})
+}
+
+/// Compiled call to REQSTART
+struct ReqStart {}
+impl CExp<i64> for ReqStart {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        // The query time is logged, so the result is the same when the transaction is replayed.
+        ee.tr.global(0)
+    }
+}
+
+/// Compile call to ELAPSEDMS.
+fn c_elapsedms(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    Box::new(ElapsedMs {})
+}
+
+/// Compiled call to ELAPSEDMS
+struct ElapsedMs {}
+impl CExp<i64> for ElapsedMs {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let mut result = 0;
+        let ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            result = ext.ctx.start.elapsed().as_millis() as i64;
+        }
+        ee.tr.set_extension(ext);
+        result
+    }
+}
+
+/// Key for SIGNTOKEN and VERIFYTOKEN ( hash of server secret ).
+static TOKEN_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Set the server secret used by SIGNTOKEN and VERIFYTOKEN.
+pub fn set_token_secret(secret: &[u8]) {
+    let key = blake2_rfc::blake2b::blake2b(32, &[], secret);
+    let _ = TOKEN_KEY.set(key.as_bytes().to_vec());
+}
+
+/// Compute token signature for payload and expiry ( as encoded in the token ), None if no secret is set.
+fn token_mac(signed: &str) -> Option<blake2_rfc::blake2b::Blake2bResult> {
+    let key = TOKEN_KEY.get()?;
+    Some(blake2_rfc::blake2b::blake2b(32, key, signed.as_bytes()))
+}
+
+/// Current time in seconds since January 1, 1970 0:00:00 UTC.
+fn unix_secs() -> i64 {
+    let now = std::time::SystemTime::now();
+    now.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+/// Compile call to SIGNTOKEN.
+fn c_signtoken(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String, DataKind::Int]);
+    let payload = c_value(b, &mut args[0]);
+    let ttl = c_int(b, &mut args[1]);
+    Box::new(SignToken { payload, ttl })
+}
+
+/// Compiled call to SIGNTOKEN
+struct SignToken {
+    payload: CExpPtr<Value>,
+    ttl: CExpPtr<i64>,
+}
+impl CExp<Value> for SignToken {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        use base64::Engine;
+        let payload = self.payload.eval(ee, d).str();
+        let ttl = self.ttl.eval(ee, d);
+        let expiry = if ttl > 0 { unix_secs() + ttl } else { 0 };
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let signed = format!("{}.{}", b64.encode(payload.as_bytes()), expiry);
+        let Some(mac) = token_mac(&signed) else {
+            panic!(
+                "SIGNTOKEN: no token secret ( set RUSTWEB_TOKEN_SECRET or --token-secret-file )"
+            );
+        };
+        let mac = b64.encode(mac.as_bytes());
+        Value::String(Rc::new(format!("{signed}.{mac}")))
+    }
+}
+
+/// Compile call to VERIFYTOKEN.
+fn c_verifytoken(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let token = c_value(b, &mut args[0]);
+    Box::new(VerifyToken { token })
+}
+
+/// Compiled call to VERIFYTOKEN
+struct VerifyToken {
+    token: CExpPtr<Value>,
+}
+impl CExp<Value> for VerifyToken {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let token = self.token.eval(ee, d).str();
+        Value::String(Rc::new(verify_token(&token).unwrap_or_default()))
+    }
+}
+
+/// Get payload of token, None if the token is invalid or has expired.
+fn verify_token(token: &str) -> Option<String> {
+    use base64::Engine;
+    let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let (signed, mac) = token.rsplit_once('.')?;
+    let (payload, expiry) = signed.split_once('.')?;
+    let mac = b64.decode(mac).ok()?;
+    if token_mac(signed)? != mac[..] {
+        return None;
+    }
+    let expiry: i64 = expiry.parse().ok()?;
+    if expiry != 0 && unix_secs() >= expiry {
+        return None;
+    }
+    String::from_utf8(b64.decode(payload).ok()?).ok()
+}
+
+/// Compile call to CTEQ.
+fn c_cteq(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    if args.len() != 2 {
+        panic!("wrong number of args");
+    }
+    for (i, e) in args.iter_mut().enumerate() {
+        let k = b.kind(e);
+        if k != DataKind::String && k != DataKind::Binary {
+            panic!(
+                "Builtin function arg {} type mismatch expected String or Binary got {:?}",
+                i + 1,
+                k
+            );
+        }
+    }
+    let x = c_value(b, &mut args[0]);
+    let y = c_value(b, &mut args[1]);
+    Box::new(CtEq { x, y })
+}
+
+/// Compiled call to CTEQ
+struct CtEq {
+    x: CExpPtr<Value>,
+    y: CExpPtr<Value>,
+}
+impl CExp<i64> for CtEq {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let x = self.x.eval(ee, d).bin();
+        let y = self.y.eval(ee, d).bin();
+        ct_eq(&x, &y) as i64
+    }
+}
+
+/// Compare a and b in time that depends only on the length of the longer input ( not on the content ).
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    let n = a.len().max(b.len());
+    let mut diff = (a.len() != b.len()) as u8;
+    for i in 0..n {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= std::hint::black_box(x ^ y);
+    }
+    diff == 0
+}
+
+/// Compile call to NUMFORMAT.
+fn c_numformat(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::Float, DataKind::String]);
+    let x = c_float(b, &mut args[0]);
+    let pattern = c_value(b, &mut args[1]);
+    Box::new(NumFormat { x, pattern })
+}
+
+/// Compiled call to NUMFORMAT
+struct NumFormat {
+    x: CExpPtr<f64>,
+    pattern: CExpPtr<Value>,
+}
+impl CExp<Value> for NumFormat {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let x = self.x.eval(ee, d);
+        let pattern = self.pattern.eval(ee, d).str();
+        Value::String(Rc::new(numformat(x, &pattern)))
+    }
+}
+
+/// Compile call to MARKDOWN.
+fn c_markdown(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let text = c_value(b, &mut args[0]);
+    Box::new(Markdown {
+        text,
+        trusted: false,
+    })
+}
+
+/// Compile call to MARKDOWNTRUSTED.
+fn c_markdown_trusted(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let text = c_value(b, &mut args[0]);
+    Box::new(Markdown {
+        text,
+        trusted: true,
+    })
+}
+
+/// Compiled call to MARKDOWN or MARKDOWNTRUSTED
+struct Markdown {
+    text: CExpPtr<Value>,
+    trusted: bool,
+}
+impl CExp<Value> for Markdown {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let text = self.text.eval(ee, d).str();
+        Value::String(Rc::new(crate::markdown::to_html(&text, self.trusted)))
+    }
+}
+
+/// Compile call to CURRENCYFORMAT.
+fn c_currencyformat(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::Float, DataKind::String]);
+    let x = c_float(b, &mut args[0]);
+    let currency = c_value(b, &mut args[1]);
+    Box::new(CurrencyFormat { x, currency })
+}
+
+/// Compiled call to CURRENCYFORMAT
+struct CurrencyFormat {
+    x: CExpPtr<f64>,
+    currency: CExpPtr<Value>,
+}
+impl CExp<Value> for CurrencyFormat {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let x = self.x.eval(ee, d);
+        let currency = self.currency.eval(ee, d).str();
+        Value::String(Rc::new(currencyformat(x, &currency)))
+    }
+}
+
+/// Compile call to NUMPARSE.
+fn c_numparse(b: &Block, args: &mut [Expr]) -> CExpPtr<f64> {
+    check_types(b, args, &[DataKind::String, DataKind::String]);
+    let s = c_value(b, &mut args[0]);
+    let locale = c_value(b, &mut args[1]);
+    Box::new(NumParse { s, locale })
+}
+
+/// Compiled call to NUMPARSE
+struct NumParse {
+    s: CExpPtr<Value>,
+    locale: CExpPtr<Value>,
+}
+impl CExp<f64> for NumParse {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> f64 {
+        let s = self.s.eval(ee, d).str();
+        let locale = self.locale.eval(ee, d).str();
+        numparse(&s, &locale)
+    }
+}
+
+/// Get ( grouping, decimal ) separators for locale.
+fn separators(locale: &str) -> (char, char) {
+    match locale {
+        "de" => ('.', ','),
+        "fr" => (' ', ','),
+        "ch" => ('\'', '.'),
+        _ => (',', '.'),
+    }
+}
+
+/// Format number using pattern such as #,##0.00 optionally followed by |locale.
+fn numformat(x: f64, pattern: &str) -> String {
+    let (pattern, locale) = pattern.split_once('|').unwrap_or((pattern, ""));
+    let (int, frac) = pattern.split_once('.').unwrap_or((pattern, ""));
+    let count = |s: &str, digits: &str| s.chars().filter(|c| digits.contains(*c)).count();
+    let (min_dec, max_dec) = (count(frac, "0"), count(frac, "0#"));
+    let grouping = int.contains(',');
+    let n = format_num(
+        x,
+        grouping,
+        count(int, "0"),
+        min_dec,
+        max_dec,
+        separators(locale),
+    );
+    sign(x, n)
+}
+
+/// Format number as amount of currency ( ISO 4217 code ).
+fn currencyformat(x: f64, currency: &str) -> String {
+    let code = currency.to_ascii_uppercase();
+    let (symbol, prefix, locale, dec) = match code.as_str() {
+        "USD" => ("$", true, "en", 2),
+        "GBP" => ("£", true, "en", 2),
+        "EUR" => (" €", false, "de", 2),
+        "JPY" => ("¥", true, "en", 0),
+        "CHF" => ("CHF ", true, "ch", 2),
+        "CAD" => ("CA$", true, "en", 2),
+        "AUD" => ("A$", true, "en", 2),
+        _ => ("", false, "en", 2),
+    };
+    let n = format_num(x, true, 1, dec, dec, separators(locale));
+    let n = if prefix {
+        format!("{symbol}{n}")
+    } else if symbol.is_empty() {
+        format!("{n} {code}")
+    } else {
+        format!("{n}{symbol}")
+    };
+    sign(x, n)
+}
+
+/// Prefix formatted number with '-' if x is negative ( and n is not zero ).
+fn sign(x: f64, n: String) -> String {
+    if x < 0.0 && n.chars().any(|c| ('1'..='9').contains(&c)) {
+        format!("-{n}")
+    } else {
+        n
+    }
+}
+
+/// Format absolute value of x.
+fn format_num(
+    x: f64,
+    grouping: bool,
+    min_int: usize,
+    min_dec: usize,
+    max_dec: usize,
+    (gs, ds): (char, char),
+) -> String {
+    if !x.is_finite() {
+        return x.abs().to_string();
+    }
+    let s = format!("{:.*}", max_dec, x.abs());
+    let (int, frac) = s.split_once('.').unwrap_or((&s, ""));
+    let mut frac = frac.to_string();
+    while frac.len() > min_dec && frac.ends_with('0') {
+        frac.pop();
+    }
+    let int = int.trim_start_matches('0');
+    let mut int = format!("{}{int}", "0".repeat(min_int.saturating_sub(int.len())));
+    if int.is_empty() && frac.is_empty() {
+        int.push('0');
+    }
+    let mut result = String::new();
+    for (i, c) in int.chars().enumerate() {
+        if grouping && i > 0 && (int.len() - i).is_multiple_of(3) {
+            result.push(gs);
+        }
+        result.push(c);
+    }
+    if !frac.is_empty() {
+        result.push(ds);
+        result.push_str(&frac);
+    }
+    result
+}
+
+/// Parse number formatted for locale, grouping separators and other characters are ignored. Result is zero if invalid.
+fn numparse(s: &str, locale: &str) -> f64 {
+    let (gs, ds) = separators(locale);
+    let t: String = s
+        .chars()
+        .filter(|c| *c != gs)
+        .filter_map(|c| match c {
+            '0'..='9' | '-' => Some(c),
+            _ if c == ds => Some('.'),
+            _ => None,
+        })
+        .collect();
+    t.parse().unwrap_or(0.0)
+}
+
+/// Compile call to ENQUEUE.
+fn c_enqueue(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::String, DataKind::String]);
+    let kind = c_value(b, &mut args[0]);
+    let payload = c_value(b, &mut args[1]);
+    Box::new(Enqueue { kind, payload })
+}
+
+/// Compiled call to ENQUEUE
+struct Enqueue {
+    kind: CExpPtr<Value>,
+    payload: CExpPtr<Value>,
+}
+impl CExp<i64> for Enqueue {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let kind = self.kind.eval(ee, d);
+        let payload = self.payload.eval(ee, d);
+        let Some(t) = ee.db.get_table(&ObjRef::new("job", "Queue")) else {
+            return 0;
+        };
+        let mut row = t.row();
+        row.id = t.alloc_id(&ee.db);
+        row.values[0] = kind;
+        row.values[1] = payload;
+        row.values[2] = Value::Int(0);
+        row.values[3] = Value::Int(0);
+        t.insert(&ee.db, &mut row);
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.tx_job = true;
+        }
+        ee.tr.set_extension(ext);
+        row.id
+    }
+}
+
+/// Compile call to SENDMAILAT.
+fn c_sendmail_at(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(
+        b,
+        args,
+        &[
+            DataKind::String,
+            DataKind::String,
+            DataKind::String,
+            DataKind::Int,
+        ],
+    );
+    let to = c_value(b, &mut args[0]);
+    let subject = c_value(b, &mut args[1]);
+    let body = c_value(b, &mut args[2]);
+    let at = c_int(b, &mut args[3]);
+    Box::new(SendMailAt {
+        to,
+        subject,
+        body,
+        at,
+    })
+}
+
+/// Compiled call to SENDMAILAT
+struct SendMailAt {
+    to: CExpPtr<Value>,
+    subject: CExpPtr<Value>,
+    body: CExpPtr<Value>,
+    at: CExpPtr<i64>,
+}
+impl CExp<i64> for SendMailAt {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let to = self.to.eval(ee, d);
+        let subject = self.subject.eval(ee, d);
+        let body = self.body.eval(ee, d);
+        let at = self.at.eval(ee, d);
+        let db = ee.db.clone();
+        let (Some(mt), Some(qt), Some(st)) = (
+            db.get_table(&ObjRef::new("email", "Msg")),
+            db.get_table(&ObjRef::new("email", "Queue")),
+            db.get_table(&ObjRef::new("email", "SmtpAccount")),
+        ) else {
+            return 0;
+        };
+        // Use the first SMTP account, with the username as the From address.
+        let Some((account, from)) = st.scan(&db).next().map(|(pp, off)| {
+            let p = pp.borrow();
+            let a = st.access(&p, off);
+            (a.id(), a.str(&db, 1))
+        }) else {
+            return 0;
+        };
+        let mut row = mt.row();
+        row.id = mt.alloc_id(&db);
+        row.values[0] = Value::String(Rc::new(from));
+        row.values[1] = to;
+        row.values[2] = subject;
+        row.values[3] = body;
+        row.values[4] = Value::Int(0);
+        row.values[5] = Value::Int(account as i64);
+        row.values[6] = Value::Int(0);
+        mt.insert(&db, &mut row);
+        let msg = row.id;
+        let mut row = qt.row();
+        row.id = qt.alloc_id(&db);
+        row.values[0] = Value::Int(msg);
+        // The at column is missing if the migration has not been applied ( e.g. on a replica ), then the email is sent immediately.
+        if let Some(&col) = qt.info.get("at") {
+            row.values[col] = Value::Int(at);
+        }
+        qt.insert(&db, &mut row);
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.tx_email = true;
+        }
+        ee.tr.set_extension(ext);
+        msg
+    }
+}
+
+/// Compile call to SETTRAILER.
+fn c_settrailer(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::String, DataKind::String]);
+    let name = c_value(b, &mut args[0]);
+    let value = c_value(b, &mut args[1]);
+    Box::new(SetTrailer { name, value })
+}
+
+/// Compiled call to SETTRAILER
+struct SetTrailer {
+    name: CExpPtr<Value>,
+    value: CExpPtr<Value>,
+}
+impl CExp<i64> for SetTrailer {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let name = self.name.eval(ee, d).str().to_string();
+        let value = self.value.eval(ee, d).str().to_string();
+        let token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if name.is_empty() || !name.chars().all(token) || value.contains(['\r', '\n']) {
+            return 0;
+        }
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.trailers.retain(|(n, _)| !n.eq_ignore_ascii_case(&name));
+            ext.trailers.push((name, value));
+        }
+        ee.tr.set_extension(ext);
+        1
+    }
+}
+
+/// Call f with the in-memory cache ( result is None if there is no shared state, e.g. when replicating ).
+fn with_cache<T>(ee: &mut EvalEnv, f: impl FnOnce(&mut crate::cache::Cache) -> T) -> Option<T> {
+    let mut result = None;
+    let ext = ee.tr.get_extension();
+    if let Some(ext) = ext.downcast_ref::<TransExt>() {
+        if let Some(ss) = &ext.ss {
+            result = Some(f(&mut ss.cache.lock().unwrap()));
+        }
+    }
+    ee.tr.set_extension(ext);
+    result
+}
+
+/// Compile call to CACHEGET.
+fn c_cacheget(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let key = c_value(b, &mut args[0]);
+    Box::new(CacheGet { key })
+}
+
+/// Compiled call to CACHEGET
+struct CacheGet {
+    key: CExpPtr<Value>,
+}
+impl CExp<Value> for CacheGet {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let key = self.key.eval(ee, d).str();
+        let result = with_cache(ee, |c| c.get(&key)).flatten();
+        Value::String(Rc::new(result.unwrap_or_default()))
+    }
+}
+
+/// Compile call to CACHESET.
+fn c_cacheset(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(
+        b,
+        args,
+        &[DataKind::String, DataKind::String, DataKind::Int],
+    );
+    let key = c_value(b, &mut args[0]);
+    let value = c_value(b, &mut args[1]);
+    let ttl = c_int(b, &mut args[2]);
+    Box::new(CacheSet { key, value, ttl })
+}
+
+/// Compiled call to CACHESET
+struct CacheSet {
+    key: CExpPtr<Value>,
+    value: CExpPtr<Value>,
+    ttl: CExpPtr<i64>,
+}
+impl CExp<i64> for CacheSet {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let key = self.key.eval(ee, d).str().to_string();
+        let value = self.value.eval(ee, d).str().to_string();
+        let ttl = self.ttl.eval(ee, d);
+        let ttl = (ttl > 0).then(|| std::time::Duration::from_secs(ttl as u64));
+        with_cache(ee, |c| c.set(key, value, ttl)).unwrap_or(false) as i64
+    }
+}
+
+/// Compile call to CACHECLEAR.
+fn c_cacheclear(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    Box::new(CacheClear {})
+}
+
+/// Compiled call to CACHECLEAR
+struct CacheClear {}
+impl CExp<i64> for CacheClear {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        with_cache(ee, |c| c.clear()).unwrap_or(0) as i64
+    }
+}
+
+/// Session data, map of key to value.
+type SessionData = std::collections::BTreeMap<String, String>;
+
+/// Check whether s is a well-formed session id ( 32 random bytes, base64url encoded ).
+pub fn is_session_id(s: &str) -> bool {
+    s.len() == 43
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Current session for SESSION builtins.
+struct Session {
+    id: String,
+    ss: Arc<SharedState>,
+    readonly: bool,
+    /// Session was created by this call.
+    new: bool,
+    /// Query time ( seconds since 1970 ).
+    now: i64,
+}
+
+/// Get current session, if there is none and create is true a new session is created and the session cookie is set.
+/// Result is None if there is no session, or SQL is not running for a request ( e.g. on a replica ).
+fn session(ee: &mut EvalEnv, create: bool) -> Option<Session> {
+    let mut result = None;
+    let now = ee.tr.global(0) / 1_000_000;
+    let mut ext = ee.tr.get_extension();
+    if let Some(ext) = ext.downcast_mut::<TransExt>() {
+        if let Some(ss) = &ext.ss {
+            // A session id from the cookie is only used if the session exists, to prevent session fixation.
+            if !ext.ctx.session_checked {
+                ext.ctx.session_checked = true;
+                let id = &ext.ctx.session;
+                if !id.is_empty() && !session_exists(&ee.db, ss, id, now) {
+                    ext.ctx.session.clear();
+                }
+            }
+            let new = ext.ctx.session.is_empty();
+            if new && create {
+                use base64::Engine;
+                let mut bytes = [0; 32];
+                if getrandom::getrandom(&mut bytes).is_ok() {
+                    let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+                    ext.ctx.session = b64.encode(bytes);
+                }
+            }
+            if !ext.ctx.session.is_empty() {
+                result = Some(Session {
+                    id: ext.ctx.session.clone(),
+                    ss: ss.clone(),
+                    readonly: ext.ctx.readonly,
+                    new,
+                    now,
+                });
+            }
+        }
+    }
+    ee.tr.set_extension(ext);
+    if let Some(s) = &result {
+        if s.new {
+            session_cookie(ee, s, s.ss.session_ttl);
+        }
+    }
+    result
+}
+
+/// Set session cookie, max_age is in seconds ( zero deletes the cookie ).
+fn session_cookie(ee: &mut EvalEnv, s: &Session, max_age: u64) {
+    let (id, secure) = if max_age == 0 {
+        ("", "")
+    } else {
+        (
+            s.id.as_str(),
+            if s.ss.session_secure { "; Secure" } else { "" },
+        )
+    };
+    let value =
+        format!("{SESSION_COOKIE}={id}; Max-Age={max_age}; Path=/; HttpOnly; SameSite=Lax{secure}");
+    ee.tr.header("set-cookie", &value);
+}
+
+/// Check whether session id exists ( and has not expired ).
+fn session_exists(db: &DB, ss: &SharedState, id: &str, now: i64) -> bool {
+    match ss.session_store {
+        SessionStore::Memory => ss
+            .cache
+            .lock()
+            .unwrap()
+            .get(&format!("session:{id}"))
+            .is_some(),
+        SessionStore::Database => session_row(db, id).is_some_and(|(_, expires)| expires > now),
+    }
+}
+
+/// Get session data from web.Session table, result is ( data, expiry time ).
+fn session_row(db: &DB, id: &str) -> Option<(String, i64)> {
+    let t = db.get_table(&ObjRef::new("web", "Session"))?;
+    let key = vec![Value::String(Rc::new(id.to_string()))];
+    let (pp, off) = t.ix_get(db, key, 0)?;
+    let p = pp.borrow();
+    let a = t.access(&p, off);
+    Some((a.str(db, 1), a.int(2)))
+}
+
+/// Load session data.
+fn session_load(ee: &mut EvalEnv, s: &Session) -> SessionData {
+    let json = match s.ss.session_store {
+        SessionStore::Memory => s.ss.cache.lock().unwrap().get(&format!("session:{}", s.id)),
+        SessionStore::Database => match session_row(&ee.db, &s.id) {
+            Some((data, expires)) if expires > s.now => Some(data),
+            _ => None,
+        },
+    };
+    json.and_then(|j| serde_json::from_str(&j).ok())
+        .unwrap_or_default()
+}
+
+/// Save session data ( None deletes the session ), result is false if the data could not be saved.
+fn session_save(ee: &mut EvalEnv, s: &Session, data: Option<&SessionData>) -> bool {
+    let ttl = s.ss.session_ttl;
+    let json = data.map(|d| serde_json::to_string(d).unwrap());
+    match s.ss.session_store {
+        SessionStore::Memory => {
+            let key = format!("session:{}", s.id);
+            let mut cache = s.ss.cache.lock().unwrap();
+            match json {
+                Some(json) => cache.set(key, json, Some(std::time::Duration::from_secs(ttl))),
+                None => {
+                    cache.remove(&key);
+                    true
+                }
+            }
+        }
+        SessionStore::Database => {
+            let db = &ee.db;
+            let Some(t) = db.get_table(&ObjRef::new("web", "Session")) else {
+                return false;
+            };
+            if s.readonly {
+                return false;
+            }
+            let sid = Value::String(Rc::new(s.id.clone()));
+            if let Some((pp, off)) = t.ix_get(db, vec![sid.clone()], 0) {
+                let mut row = t.row();
+                row.load(db, &pp.borrow().data[off..]);
+                t.remove(db, &row);
+            }
+            if let Some(json) = json {
+                let mut row = t.row();
+                row.id = t.alloc_id(db);
+                row.values[0] = sid;
+                row.values[1] = Value::String(Rc::new(json));
+                row.values[2] = Value::Int(s.now + ttl as i64);
+                t.insert(db, &mut row);
+            }
+            true
+        }
+    }
+}
+
+/// Compile call to SESSION.
+fn c_session(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    Box::new(SessionId {})
+}
+
+/// Compiled call to SESSION
+struct SessionId {}
+impl CExp<Value> for SessionId {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let id = session(ee, true).map(|s| s.id);
+        Value::String(Rc::new(id.unwrap_or_default()))
+    }
+}
+
+/// Compile call to SESSIONGET.
+fn c_sessionget(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let key = c_value(b, &mut args[0]);
+    Box::new(SessionGet { key })
+}
+
+/// Compiled call to SESSIONGET
+struct SessionGet {
+    key: CExpPtr<Value>,
+}
+impl CExp<Value> for SessionGet {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let key = self.key.eval(ee, d).str();
+        let mut result = None;
+        if let Some(s) = session(ee, false) {
+            result = session_load(ee, &s).remove(key.as_str());
+        }
+        Value::String(Rc::new(result.unwrap_or_default()))
+    }
+}
+
+/// Compile call to SESSIONSET.
+fn c_sessionset(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::String, DataKind::String]);
+    let key = c_value(b, &mut args[0]);
+    let value = c_value(b, &mut args[1]);
+    Box::new(SessionSet { key, value })
+}
+
+/// Compiled call to SESSIONSET
+struct SessionSet {
+    key: CExpPtr<Value>,
+    value: CExpPtr<Value>,
+}
+impl CExp<i64> for SessionSet {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let key = self.key.eval(ee, d).str().to_string();
+        let value = self.value.eval(ee, d).str().to_string();
+        let Some(s) = session(ee, true) else {
+            return 0;
+        };
+        let mut data = session_load(ee, &s);
+        if value.is_empty() {
+            data.remove(&key);
+        } else {
+            data.insert(key, value);
+        }
+        if !session_save(ee, &s, Some(&data)) {
+            return 0;
+        }
+        if !s.new {
+            // Extend cookie expiry.
+            session_cookie(ee, &s, s.ss.session_ttl);
+        }
+        1
+    }
+}
+
+/// Compile call to SESSIONDESTROY.
+fn c_sessiondestroy(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    Box::new(SessionDestroy {})
+}
+
+/// Compiled call to SESSIONDESTROY
+struct SessionDestroy {}
+impl CExp<i64> for SessionDestroy {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let Some(s) = session(ee, false) else {
+            return 0;
+        };
+        let ok = session_save(ee, &s, None);
+        session_cookie(ee, &s, 0);
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.ctx.session.clear();
+        }
+        ee.tr.set_extension(ext);
+        ok as i64
+    }
+}
+
+/// IP geolocation database for GEOIP.
+static GEOIP: OnceLock<crate::geoip::GeoIp> = OnceLock::new();
+
+/// Set the database used by GEOIP.
+pub fn set_geoip(g: crate::geoip::GeoIp) {
+    let _ = GEOIP.set(g);
+}
+
+/// Compile call to GEOIP, the path argument is optional.
+fn c_geoip(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    if args.len() == 1 {
+        check_types(b, args, &[DataKind::String]);
+    } else {
+        check_types(b, args, &[DataKind::String, DataKind::String]);
+    }
+    let ip = c_value(b, &mut args[0]);
+    let path = args.get_mut(1).map(|a| c_value(b, a));
+    Box::new(GeoIpLookup { ip, path })
+}
+
+/// Compiled call to GEOIP
+struct GeoIpLookup {
+    ip: CExpPtr<Value>,
+    path: Option<CExpPtr<Value>>,
+}
+impl CExp<Value> for GeoIpLookup {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let mut ip = self.ip.eval(ee, d).str().to_string();
+        let path = match &self.path {
+            Some(p) => p.eval(ee, d).str().to_string(),
+            None => String::new(),
+        };
+        if ip.is_empty() {
+            let ext = ee.tr.get_extension();
+            if let Some(ext) = ext.downcast_ref::<TransExt>() {
+                ip = ext.ctx.ip.clone();
+            }
+            ee.tr.set_extension(ext);
+        }
+        let path = if path.is_empty() {
+            "country.iso_code"
+        } else {
+            &path
+        };
+        let path: Vec<&str> = path.split('.').collect();
+        let result = GEOIP.get().and_then(|g| g.lookup(&ip, &path));
+        Value::String(Rc::new(result.unwrap_or_default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levenshtein_distance() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("abc", ""), 3);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("flaw", "lawn"), 2);
+        assert_eq!(levenshtein("abc", "abc"), 0);
+        // Chars, not bytes, are compared.
+        assert_eq!(levenshtein("café", "cafe"), 1);
+        assert_eq!(levenshtein("日本語", "日本"), 1);
+        // Only the first FUZZY_MAX chars are compared.
+        let a = "x".repeat(FUZZY_MAX);
+        assert_eq!(levenshtein(&a, &(a.clone() + "yyy")), 0);
+    }
+
+    #[test]
+    fn similarity_percent() {
+        assert_eq!(similarity("", ""), 100);
+        assert_eq!(similarity("abc", "abc"), 100);
+        assert_eq!(similarity("abc", ""), 0);
+        assert_eq!(similarity("abcd", "abce"), 75);
+        assert_eq!(similarity("naïve", "naive"), 80);
+    }
+
+    #[test]
+    fn soundex_code() {
+        assert_eq!(soundex("Robert"), "R163");
+        assert_eq!(soundex("Rupert"), "R163");
+        assert_eq!(soundex("Tymczak"), "T522");
+        assert_eq!(soundex("Pfister"), "P236");
+        assert_eq!(soundex("Ashcraft"), "A261");
+        assert_eq!(soundex("Lee"), "L000");
+        assert_eq!(soundex("  o'brien "), "O165");
+        assert_eq!(soundex(""), "");
+        assert_eq!(soundex("123"), "");
+        // Non-ASCII letters are ignored.
+        assert_eq!(soundex("Müller"), "M460");
+        assert_eq!(soundex("日本"), "");
+    }
+
+    #[test]
+    fn numformat_patterns() {
+        assert_eq!(numformat(1234567.891, "#,##0.00"), "1,234,567.89");
+        assert_eq!(numformat(1234567.891, "#,##0.00|de"), "1.234.567,89");
+        assert_eq!(numformat(1234567.891, "#,##0.00|fr"), "1 234 567,89");
+        assert_eq!(numformat(1234567.891, "#,##0.00|ch"), "1'234'567.89");
+        assert_eq!(numformat(1.5, "0.0#"), "1.5");
+        assert_eq!(numformat(1.257, "0.0#"), "1.26");
+        assert_eq!(numformat(7.0, "000"), "007");
+        assert_eq!(numformat(0.25, "#.##"), ".25");
+        assert_eq!(numformat(0.0, "#"), "0");
+        assert_eq!(numformat(-12.5, "#,##0"), "-12");
+        assert_eq!(numformat(-0.001, "0.00"), "0.00");
+        assert_eq!(numformat(f64::INFINITY, "0"), "inf");
+    }
+
+    #[test]
+    fn currencyformat_codes() {
+        assert_eq!(currencyformat(1234.5, "usd"), "$1,234.50");
+        assert_eq!(currencyformat(-1234.5, "GBP"), "-£1,234.50");
+        assert_eq!(currencyformat(1234.5, "EUR"), "1.234,50 €");
+        assert_eq!(currencyformat(1234.6, "JPY"), "¥1,235");
+        assert_eq!(currencyformat(1234.5, "CHF"), "CHF 1'234.50");
+        assert_eq!(currencyformat(1234.5, "NZD"), "1,234.50 NZD");
+    }
+
+    #[test]
+    fn numparse_round_trip() {
+        for x in [0.0, 1.0, -1.0, 0.5, 1234567.89, -9876.54] {
+            for locale in ["en", "de", "fr", "ch"] {
+                let s = numformat(x, &format!("#,##0.00|{locale}"));
+                assert_eq!(numparse(&s, locale), x, "{s} {locale}");
+            }
+        }
+        assert_eq!(numparse(&currencyformat(-1234.5, "EUR"), "de"), -1234.5);
+        assert_eq!(numparse(&currencyformat(1234.5, "USD"), "en"), 1234.5);
+        assert_eq!(numparse("", "en"), 0.0);
+        assert_eq!(numparse("abc", "en"), 0.0);
+        assert_eq!(numparse("1.2.3", "en"), 0.0);
+    }
+}
@@ -0,0 +1,167 @@
+use rustc_hash::FxHashMap as HashMap;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// In-memory key-value cache with per-entry expiry, least recently used entries are evicted when the size limit is reached.
+/// The cache is not saved to the database, so is lost when the server restarts and is not replicated.
+pub struct Cache {
+    /// Maximum total size of keys and values ( bytes ).
+    limit: usize,
+    /// Current total size of keys and values ( bytes ).
+    size: usize,
+    /// Counter used to order entries by last use.
+    tick: u64,
+    map: HashMap<String, Entry>,
+    /// Keys ordered by last use.
+    lru: BTreeMap<u64, String>,
+}
+
+struct Entry {
+    value: String,
+    expires: Option<Instant>,
+    used: u64,
+}
+
+impl Cache {
+    /// Construct cache with size limit in bytes ( zero means the cache is disabled ).
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            size: 0,
+            tick: 0,
+            map: HashMap::default(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    /// Get value for key, if present and not expired.
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        let e = self.map.get_mut(key)?;
+        if e.expires.is_some_and(|t| t <= Instant::now()) {
+            self.remove(key);
+            return None;
+        }
+        self.lru.remove(&e.used);
+        self.tick += 1;
+        e.used = self.tick;
+        self.lru.insert(self.tick, key.to_string());
+        Some(e.value.clone())
+    }
+
+    /// Set value for key, expiring after ttl ( None means no expiry ). Result is false if the entry is too large.
+    pub fn set(&mut self, key: String, value: String, ttl: Option<Duration>) -> bool {
+        self.remove(&key);
+        let n = key.len() + value.len();
+        if n > self.limit {
+            return false;
+        }
+        while self.size + n > self.limit {
+            let (_, k) = self.lru.pop_first().unwrap();
+            let e = self.map.remove(&k).unwrap();
+            self.size -= k.len() + e.value.len();
+        }
+        self.tick += 1;
+        self.size += n;
+        self.lru.insert(self.tick, key.clone());
+        let e = Entry {
+            value,
+            expires: ttl.map(|d| Instant::now() + d),
+            used: self.tick,
+        };
+        self.map.insert(key, e);
+        true
+    }
+
+    /// Remove entry for key.
+    pub fn remove(&mut self, key: &str) {
+        if let Some(e) = self.map.remove(key) {
+            self.lru.remove(&e.used);
+            self.size -= key.len() + e.value.len();
+        }
+    }
+
+    /// Remove all entries, result is the number of entries removed.
+    pub fn clear(&mut self) -> usize {
+        let n = self.map.len();
+        self.map.clear();
+        self.lru.clear();
+        self.size = 0;
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(c: &mut Cache, key: &str, value: &str) -> bool {
+        c.set(key.to_string(), value.to_string(), None)
+    }
+
+    #[test]
+    fn get_set() {
+        let mut c = Cache::new(100);
+        assert!(set(&mut c, "a", "1"));
+        assert_eq!(c.get("a").as_deref(), Some("1"));
+        assert!(set(&mut c, "a", "22"));
+        assert_eq!(c.get("a").as_deref(), Some("22"));
+        assert_eq!(c.size, 3);
+        c.remove("a");
+        assert_eq!(c.get("a"), None);
+        assert_eq!(c.size, 0);
+    }
+
+    #[test]
+    fn eviction_order() {
+        // Each entry is 10 bytes, so three fit.
+        let mut c = Cache::new(30);
+        set(&mut c, "a", "123456789");
+        set(&mut c, "b", "123456789");
+        set(&mut c, "c", "123456789");
+        // Use a, so b is the least recently used.
+        assert!(c.get("a").is_some());
+        set(&mut c, "d", "123456789");
+        assert_eq!(c.get("b"), None);
+        assert!(c.get("c").is_some());
+        // Now a is the least recently used.
+        set(&mut c, "e", "123456789");
+        assert_eq!(c.get("a"), None);
+        assert!(c.get("c").is_some() && c.get("d").is_some() && c.get("e").is_some());
+        // The gets above leave e as the most recently used, a larger entry evicts as many entries as needed.
+        set(&mut c, "f", "1234567890123456789");
+        assert_eq!(c.get("c"), None);
+        assert_eq!(c.get("d"), None);
+        assert!(c.get("e").is_some() && c.get("f").is_some());
+        assert_eq!(c.size, 30);
+    }
+
+    #[test]
+    fn too_large() {
+        let mut c = Cache::new(10);
+        set(&mut c, "a", "1");
+        assert!(!set(&mut c, "b", "1234567890"));
+        assert!(c.get("a").is_some());
+        // Replacing an entry with a value which is too large removes it.
+        assert!(!set(&mut c, "a", "1234567890"));
+        assert_eq!(c.get("a"), None);
+        // A zero limit disables the cache.
+        let mut c = Cache::new(0);
+        assert!(!set(&mut c, "a", ""));
+    }
+
+    #[test]
+    fn expiry() {
+        let mut c = Cache::new(100);
+        c.set("a".to_string(), "1".to_string(), Some(Duration::ZERO));
+        c.set(
+            "b".to_string(),
+            "2".to_string(),
+            Some(Duration::from_secs(60)),
+        );
+        assert_eq!(c.get("a"), None);
+        assert_eq!(c.get("b").as_deref(), Some("2"));
+        assert_eq!(c.size, 2);
+        assert_eq!(c.clear(), 1);
+        assert_eq!(c.get("b"), None);
+    }
+}
@@ -0,0 +1,115 @@
+use maxminddb::PathElement;
+use std::net::IpAddr;
+
+/// IP geolocation database in MaxMind DB format ( e.g. GeoLite2-Country.mmdb ), held in memory.
+pub struct GeoIp {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    /// Load database from file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| e.to_string())?;
+        Self::from_bytes(data)
+    }
+
+    /// Construct from database file contents.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, String> {
+        let reader = maxminddb::Reader::from_source(data).map_err(|e| e.to_string())?;
+        Ok(Self { reader })
+    }
+
+    /// Look up ip, path is a list of map keys or array indexes, e.g. country, iso_code.
+    /// Result is None if the ip is not found, the path does not lead to a string or number, or the database is malformed.
+    pub fn lookup(&self, ip: &str, path: &[&str]) -> Option<String> {
+        let ip = ip.trim().parse::<IpAddr>().ok()?;
+        let path: Vec<PathElement> = path
+            .iter()
+            .map(|p| match p.parse::<usize>() {
+                Ok(i) => PathElement::Index(i),
+                Err(_) => PathElement::Key(p),
+            })
+            .collect();
+        let result = self.reader.lookup(ip).ok()?;
+        match result.decode_path::<serde_json::Value>(&path).ok()?? {
+            serde_json::Value::String(s) => Some(s),
+            serde_json::Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test database ( made by testdata/make-geoip-test.py ): 1.2.3.0/24 is AU, 2001:db8::/32 is DE.
+    const TEST_DB: &[u8] = include_bytes!("../testdata/geoip-test.mmdb");
+
+    fn db() -> GeoIp {
+        GeoIp::from_bytes(TEST_DB.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn ipv4_hit() {
+        let g = db();
+        assert_eq!(
+            g.lookup("1.2.3.4", &["country", "iso_code"]).as_deref(),
+            Some("AU")
+        );
+        assert_eq!(
+            g.lookup(" 1.2.3.255 ", &["country", "names", "en"])
+                .as_deref(),
+            Some("Australia")
+        );
+        assert_eq!(
+            g.lookup("1.2.3.4", &["subdivisions", "0", "iso_code"])
+                .as_deref(),
+            Some("NSW")
+        );
+        assert_eq!(
+            g.lookup("1.2.3.4", &["n", "big"]).as_deref(),
+            Some("123456789")
+        );
+        assert_eq!(g.lookup("1.2.3.4", &["n", "f"]).as_deref(), Some("1.5"));
+    }
+
+    #[test]
+    fn ipv6_hit() {
+        let g = db();
+        assert_eq!(
+            g.lookup("2001:db8::1", &["country", "iso_code"]).as_deref(),
+            Some("DE")
+        );
+    }
+
+    #[test]
+    fn miss() {
+        let g = db();
+        assert_eq!(g.lookup("1.2.4.1", &["country", "iso_code"]), None);
+        assert_eq!(g.lookup("2001:db9::1", &["country", "iso_code"]), None);
+        assert_eq!(g.lookup("not an ip", &["country", "iso_code"]), None);
+        assert_eq!(g.lookup("1.2.3.4", &["country", "missing"]), None);
+        assert_eq!(
+            g.lookup("1.2.3.4", &["subdivisions", "1", "iso_code"]),
+            None
+        );
+        // A map is not a string or number.
+        assert_eq!(g.lookup("1.2.3.4", &["country"]), None);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(GeoIp::from_bytes(Vec::new()).is_err());
+        assert!(GeoIp::from_bytes(TEST_DB[..TEST_DB.len() - 20].to_vec()).is_err());
+
+        // Replace the shared "iso_code" key string with a pointer to itself.
+        let mut data = TEST_DB.to_vec();
+        let i = data.windows(9).position(|w| w == b"\x48iso_code").unwrap();
+        data[i] = 0x20;
+        data[i + 1] = 0;
+        let g = GeoIp::from_bytes(data).unwrap();
+        assert_eq!(g.lookup("1.2.3.4", &["country", "iso_code"]), None);
+        assert_eq!(g.lookup("2001:db8::1", &["country", "iso_code"]), None);
+    }
+}
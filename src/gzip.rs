@@ -0,0 +1,183 @@
+use rustdb::gentrans::GenResponse;
+
+/// Compression level.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// No compression.
+    Off,
+    /// Fast compression ( short match search, no lazy matching ).
+    Fast,
+    /// Default compression.
+    Default,
+}
+
+/// Minimum size of response body that is compressed.
+const MIN_SIZE: usize = 1024;
+
+/// Compress response body with gzip if the content type is compressible, setting Content-Encoding.
+pub fn apply(rp: &mut GenResponse, level: Level) {
+    if level == Level::Off || rp.output.len() < MIN_SIZE {
+        return;
+    }
+    let mut ct = None;
+    for (name, value) in &rp.headers {
+        if name.eq_ignore_ascii_case("content-encoding") {
+            return; // Already encoded.
+        } else if name.eq_ignore_ascii_case("content-type") {
+            ct = Some(value.split(';').next().unwrap().trim().to_ascii_lowercase());
+        }
+    }
+    if ct.is_some_and(|ct| compressible(&ct)) {
+        rp.output = gzip(&rp.output, level);
+        rp.headers
+            .push(("Content-Encoding".to_string(), "gzip".to_string()));
+        rp.headers
+            .push(("Vary".to_string(), "Accept-Encoding".to_string()));
+    }
+}
+
+/// Check whether content type is worth compressing ( images etc. are usually already compressed ).
+fn compressible(ct: &str) -> bool {
+    ct.starts_with("text/")
+        || ct.ends_with("+xml")
+        || ct.ends_with("+json")
+        || matches!(
+            ct,
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+/// Get gzip ( RFC 1952 ) encoding of data.
+pub fn gzip(data: &[u8], level: Level) -> Vec<u8> {
+    use std::io::Write;
+    let level = match level {
+        Level::Fast => flate2::Compression::fast(),
+        _ => flate2::Compression::default(),
+    };
+    let mut e = flate2::write::GzEncoder::new(Vec::with_capacity(data.len() / 2), level);
+    e.write_all(data).unwrap();
+    e.finish().unwrap()
+}
+
+/// Reason decompression failed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InflateError {
+    /// Data is not valid gzip.
+    Invalid,
+    /// Decompressed size exceeds the limit.
+    TooLarge,
+}
+
+/// Decompress gzip data ( one or more members ), failing as soon as the output would exceed limit bytes.
+pub fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    use std::io::Read;
+    let mut out = Vec::new();
+    let d = flate2::read::MultiGzDecoder::new(data);
+    d.take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|_| InflateError::Invalid)?;
+    if out.len() > limit {
+        return Err(InflateError::TooLarge);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..2000 {
+            data.extend_from_slice(format!("line {i} of some text\n").as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample();
+        for level in [Level::Fast, Level::Default] {
+            let z = gzip(&data, level);
+            assert!(z.len() < data.len() / 4);
+            assert_eq!(gunzip(&z, data.len()), Ok(data.clone()));
+        }
+        assert_eq!(gunzip(&gzip(b"", Level::Default), 0), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn multiple_members() {
+        let mut z = gzip(b"hello ", Level::Default);
+        z.extend(gzip(b"world", Level::Fast));
+        assert_eq!(gunzip(&z, 100), Ok(b"hello world".to_vec()));
+    }
+
+    #[test]
+    fn truncated() {
+        let z = gzip(&sample(), Level::Default);
+        for n in [0, 5, 10, z.len() / 2, z.len() - 4, z.len() - 1] {
+            assert_eq!(
+                gunzip(&z[..n], 1 << 20),
+                Err(InflateError::Invalid),
+                "n={n}"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_crc() {
+        let mut z = gzip(&sample(), Level::Default);
+        let n = z.len();
+        z[n - 8] ^= 1;
+        assert_eq!(gunzip(&z, 1 << 20), Err(InflateError::Invalid));
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut z = gzip(b"hello", Level::Default);
+        z[0] = 0;
+        assert_eq!(gunzip(&z, 100), Err(InflateError::Invalid));
+        let mut z = gzip(b"hello", Level::Default);
+        z.push(0);
+        assert_eq!(gunzip(&z, 100), Err(InflateError::Invalid));
+    }
+
+    #[test]
+    fn limit() {
+        let data = vec![0; 100_000];
+        let z = gzip(&data, Level::Default);
+        assert!(z.len() < 1000);
+        assert_eq!(gunzip(&z, data.len()), Ok(data.clone()));
+        assert_eq!(gunzip(&z, data.len() - 1), Err(InflateError::TooLarge));
+        assert_eq!(gunzip(&z, 1000), Err(InflateError::TooLarge));
+    }
+
+    #[test]
+    fn apply_compressible() {
+        let mut rp = rustdb::GenTransaction::new().rp;
+        rp.headers.push((
+            "Content-Type".to_string(),
+            "text/html; charset=utf-8".to_string(),
+        ));
+        rp.output = sample();
+        apply(&mut rp, Level::Default);
+        assert!(rp
+            .headers
+            .contains(&("Content-Encoding".to_string(), "gzip".to_string())));
+        assert_eq!(gunzip(&rp.output, 1 << 20), Ok(sample()));
+
+        // Images are not compressed, nor are small bodies.
+        let mut rp = rustdb::GenTransaction::new().rp;
+        rp.headers
+            .push(("Content-Type".to_string(), "image/png".to_string()));
+        rp.output = sample();
+        apply(&mut rp, Level::Default);
+        assert_eq!(rp.output, sample());
+        let mut rp = rustdb::GenTransaction::new().rp;
+        rp.headers
+            .push(("Content-Type".to_string(), "text/plain".to_string()));
+        rp.output = b"short".to_vec();
+        apply(&mut rp, Level::Default);
+        assert_eq!(rp.output, b"short");
+    }
+}
@@ -216,7 +216,7 @@ BEGIN
     BEGIN
       SET tname = sys.TableName(t) 
       SET filter = CASE
-        WHEN tname = '[log].[Transaction]'
+        WHEN tname = '[log].[Transaction]' OR tname = '[log].[Audit]'
           OR tname = '[browse].[Column]' OR tname = '[browse].[Table]' THEN ' WHERE false'
         ELSE '' END
     END  
@@ -776,6 +776,9 @@ GO
 CREATE INDEX [ByPath] ON [web].[File]([Path])
 GO
 
+CREATE TABLE [web].[Hook]([Table] string,[Sql] string) 
+GO
+
 CREATE FN [web].[Attr]( s string ) RETURNS string AS
 BEGIN
   SET s = REPLACE( s, '&', '&amp;' )
@@ -2366,7 +2369,7 @@ GO
 CREATE TABLE [email].[Msg]([from] string,[to] string,[title] string,[body] string,[format] int(1),[account] int,[status] int) 
 GO
 
-CREATE TABLE [email].[Queue]([msg] int) 
+CREATE TABLE [email].[Queue]([msg] int,[at] int) 
 GO
 
 CREATE TABLE [email].[SendError]([msg] int,[error] string,[time] int) 
@@ -2498,7 +2501,7 @@ GO
 INSERT INTO [email].[Msg](Id,[from],[to],[title],[body],[format],[account],[status]) VALUES 
 GO
 
-INSERT INTO [email].[Queue](Id,[msg]) VALUES 
+INSERT INTO [email].[Queue](Id,[msg],[at]) VALUES 
 GO
 
 INSERT INTO [email].[SendError](Id,[msg],[error],[time]) VALUES 
@@ -2671,6 +2674,7 @@ BEGIN
   | <a target=_blank href=/admin-ScriptSystem>Script System</a>    
   | <a target=_blank href=/log-getall>Exact</a>
 <p><a href=/admin-CheckAll>Check all functions compile ok</a> 
+<p><a href=/admin-CacheClear>Clear cache</a> 
 <h3>Schemas</h3>'
 
    SELECT '<a href=/admin-Schema?s=' | Name | '>' | Name | '</a> | ' FROM sys.Schema ORDER BY Name
@@ -2679,6 +2683,26 @@ BEGIN
 END
 GO
 
+CREATE FN [admin].[/admin-CacheClear]() AS 
+BEGIN
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC admin.Head('Clear cache')
+  SELECT '<p>Removed ' | CACHECLEAR() | ' cache entries.'
+  EXEC admin.Trailer()
+END
+GO
+
+CREATE FN [admin].[/admin-Sync]() AS 
+BEGIN
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  -- On a replica, the number of transactions saved is appended to the output.
+  EXEC web.SetContentType( 'text/plain; charset=utf-8' )
+  DECLARE dummy int SET dummy = REPSYNC()
+END
+GO
+
 CREATE FN [admin].[/admin-CheckAll]() AS 
 BEGIN
   DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
@@ -2895,6 +2919,35 @@ SELECT '<h1>Manual</h1>
 <li>EXCEPTION() returns a string with any error that occurred during an EXECUTE statement.</li>
 <li>REPACKFILE(k,schema,table) : A file is re-packed to free up pages. The result is an integer, the number of pages freed, or -1 if the table or index does not exist. k=0 => main file, k=1.. => an index, k in -4..-1 => byte storage files. 
 <li>VERIFYDB() : verifies the logical page structure of the database. , the result is a string. Note: this needs exclusive access to the database to give consistent results, as it can observe update activity in shared data structures. Calling it while another process is updating the database may result in an exception.
+<li>LEVENSHTEIN( a string, b string ) : returns the edit distance between a and b, the minimum number of single character insertions, deletions or substitutions needed to change a into b. Comparison is case sensitive. Only the first 256 characters of each string are compared.</li>
+<li>SIMILARITY( a string, b string ) : returns a percentage 0..100, computed as 100 - 100 * LEVENSHTEIN(a,b) / n where n is the length of the longer string ( limited to 256 ). Two empty strings have similarity 100.</li>
+<li>SOUNDEX( s string ) : returns the American Soundex code of s, the first letter followed by three digits. Non-letters are ignored, H and W do not separate letters with the same code. Returns an empty string if s contains no letters.</li>
+<li>PAGESIZE( size int ) : returns size clamped to the range 1..1000, a size less than 1 gives the default page size 20.</li>
+<li>PAGEOFFSET( page int, size int ) : returns the number of rows to skip for page ( numbered from 1 ) with the page size PAGESIZE(size). A page less than 1 is treated as 1, and the result is at most 1,000,000 ( the offset of the last page that starts at or below 1,000,000 ).</li>
+<li>PAGECOUNT( count int, size int ) : returns the number of pages needed for count rows with page size PAGESIZE(size), at least 1.</li>
+<li>REQSTART() : returns the time the http request was received, in micro-seconds since January 1, 1970 0:00:00 UTC. This is the query time, which is logged, so the result is the same when the transaction is replayed on a replica.</li>
+<li>ELAPSEDMS() : returns the number of milli-seconds since the server started handling the http request. This uses a monotonic clock, so is not affected by system clock adjustments.</li>
+<li>NUMFORMAT( x float, pattern string ) : formats x using a pattern such as #,##0.00 ( 0 is a required digit, # an optional digit, a comma in the integer part means digits are grouped in threes ). The pattern may be followed by |de ( 1.234,56 ), |fr ( 1 234,56 ) or |ch ( 1''234.56 ) to use those separators.</li>
+<li>CURRENCYFORMAT( x float, currency string ) : formats x as an amount of the currency ( ISO 4217 code ), e.g. $1,234.56 for USD, 1.234,56 € for EUR. USD, GBP, EUR, JPY, CHF, CAD and AUD have symbols, other codes are formatted as 1,234.56 SEK.</li>
+<li>MARKDOWN( text string ) : renders Markdown ( CommonMark ) text as HTML. HTML in the text is escaped, and the result is sanitised so that only safe elements and attributes are kept, and links or images with a URL scheme other than http, https or mailto have the URL removed, so the result is safe to include in a page even if the text was submitted by a user. Tables and ~~strikethrough~~ are also supported. Text longer than 1MB is returned escaped inside a pre element.</li>
+<li>MARKDOWNTRUSTED( text string ) : as MARKDOWN, but HTML in the text is passed through unchanged and the result is not sanitised. Only use this for trusted content.</li>
+<li>NUMPARSE( s string, locale string ) : parses a number formatted as by NUMFORMAT for the locale ( empty for 1,234.56 or de, fr, ch ), grouping separators and other characters such as currency symbols are ignored. Returns 0 if s is not a valid number.</li>
+<li>SIGNTOKEN( payload string, ttl int ) : returns a token containing payload, signed using the server secret. If ttl is more than zero the token expires after ttl seconds. The server secret must be set ( see README ), otherwise an error occurs.</li>
+<li>VERIFYTOKEN( token string ) : returns the payload of a token created by SIGNTOKEN, or an empty string if the signature is not valid, the token has expired or no server secret is set.</li>
+<li>CTEQ( a string, b string ) : returns 1 if a equals b, otherwise 0. The comparison takes time which depends only on the length of the longer argument, not on where the arguments differ, so should be used to compare secrets such as tokens or password hashes. The lengths are not hidden. The arguments may also both be binary.</li>
+<li>SETTRAILER( name string, value string ) : sets a response trailer, sent after the body if trailers are enabled ( see README ) and the request has TE: trailers, otherwise it is sent as a header. Returns 0 if name is not a valid header name or value contains a line break.</li>
+<li>GEOIP( ip string ) : returns the country ISO code ( e.g. GB ) for ip ( empty means the client IP address ), using the IP geolocation database ( see README ).</li>
+<li>GEOIP( ip string, path string ) : as GEOIP( ip ), but returns the value at path, a list of keys or array indexes separated by dots, empty means country.iso_code, for example subdivisions.0.iso_code or city.names.en. Returns an empty string if there is no database, the ip is not found or the path does not lead to a string or number.</li>
+<li>ENQUEUE( kind string, payload string ) : adds a job to job.Queue, returns the job Id ( or 0 if there is no job.Queue table ). The job is run later by the server, using the Sql in job.Handler for the kind, which can get the payload using ARG(1,''payload''). Should be called from an update ( POST ) request.</li>
+<li>SENDMAILAT( to string, subject string, body string, send_time int ) : adds a plain text email to email.Msg and email.Queue to be sent once date.Ticks() reaches send_time ( 0 means immediately ), returns the email.Msg Id ( or 0 if there is no email.SmtpAccount ). The email is sent using the first email.SmtpAccount, with the account username as the From address. Should be called from an update ( POST ) request.</li>
+<li>BULK( secs int ) : puts the server in bulk mode for secs seconds ( 0 ends bulk mode ), for bulk imports. In bulk mode update transactions are saved in larger batches and jobs are deferred ( see README ). Returns 1 ( 0 if there is no server ).</li>
+<li>CACHESET( key string, value string, ttl int ) : stores value in the in-memory cache. If ttl is more than zero the entry expires after ttl seconds. Returns 1 if the value was stored, 0 if it is too large ( or the cache is disabled ). The cache is not saved in the database, so is lost when the server restarts, and is not replicated. When the cache is full, the least recently used entries are removed.</li>
+<li>CACHEGET( key string ) : returns the value stored in the in-memory cache for key, or an empty string if there is no entry or it has expired.</li>
+<li>CACHECLEAR() : removes all entries from the in-memory cache, returns the number of entries removed.</li>
+<li>SESSION() : returns the session id. If the request has no session cookie ( sid ), or the session does not exist ( it has no data or has expired ), a new random session id is created and the cookie is set ( HttpOnly, SameSite=Lax ).</li>
+<li>SESSIONGET( key string ) : returns the value stored for key in the current session, or an empty string.</li>
+<li>SESSIONSET( key string, value string ) : stores value for key in the current session ( creating a session if needed ), an empty value removes the key. Returns 1 if the session was saved. With --session-store database, the session is only saved by requests that can update the database ( e.g. POST ).</li>
+<li>SESSIONDESTROY() : deletes the current session data and the session cookie. Returns 1 if there was a session and it was deleted.</li>
 <li>See the web schema for functions that can be used to access http requests.</li>
 </ul>
 <h3>Conversions</h3>
@@ -3082,6 +3135,9 @@ GO
 CREATE SCHEMA [log]
 GO
 
+CREATE TABLE [log].[Audit]([time] int,[uid] string,[ip] string,[sql] string,[path] string,[args] string,[status] int,[hash] binary) 
+GO
+
 CREATE TABLE [log].[Status]([Done] int) 
 GO
 
@@ -3100,7 +3156,7 @@ BEGIN
 
   IF id = k 
     SELECT d
-  ELSE
+  ELSE IF web.Query('check') = ''
   BEGIN
     DECLARE dummy int SET dummy = TRANSWAIT()
   END
@@ -3144,7 +3200,6 @@ END
 GO
 
 CREATE FN [log].[Roll]() AS 
-() AS 
 BEGIN
   -- This applies all updates. Updates may need to be limited or filtered in some way.  
   DECLARE nt int, a int, d binary
@@ -3170,6 +3225,9 @@ BEGIN
 END
 GO
 
+INSERT INTO [log].[Audit](Id,[time],[uid],[ip],[sql],[path],[args],[status],[hash]) VALUES 
+GO
+
 INSERT INTO [log].[Status](Id,[Done]) VALUES 
 GO
 
@@ -3409,3 +3467,28 @@ SET sid = Id FROM sys.Schema WHERE Name = 'log'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Transaction'
 GO
 "###;
+
+/// SQL to create the job schema used by ENQUEUE ( run at startup if job.Queue does not exist, it is not part of INITSQL ).
+pub const JOB_SCHEMA: &str = "CREATE SCHEMA [job]
+GO
+CREATE TABLE [job].[Failed]([Kind] string,[Payload] string,[Error] string,[Time] int)
+GO
+CREATE TABLE [job].[Handler]([Kind] string,[Sql] string,[MaxTries] int)
+GO
+CREATE TABLE [job].[Queue]([Kind] string,[Payload] string,[Tries] int,[At] int)
+GO
+CREATE FN [job].[Failed]( id int, error string ) AS
+BEGIN
+  -- Called from Rust tasks::job_loop when a job fails.
+  -- The job is retried after 60 seconds times the number of tries, until MaxTries is reached.
+  DECLARE k string, p string, n int, m int
+  SET k = Kind, p = Payload, n = Tries + 1 FROM job.Queue WHERE Id = id
+  SET m = MaxTries FROM job.Handler WHERE Kind = k
+  IF n >= m
+  BEGIN
+    DELETE FROM job.Queue WHERE Id = id
+    INSERT INTO job.Failed( Kind, Payload, Error, Time ) VALUES ( k, p, error, date.Ticks() )
+  END
+  ELSE
+    UPDATE job.Queue SET Tries = n, At = date.Ticks() + 60 * 1000000 * n WHERE Id = id
+END";
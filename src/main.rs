@@ -1,14 +1,18 @@
 use rustc_hash::FxHashMap as HashMap;
 use rustdb::{
-    AccessPagedData, AtomicFile, BlockPageStg, Database, Limits, MultiFileStorage, ObjRef,
-    PageStorage, SharedPagedData, SimpleFileStorage, Value, DB,
+    AccessPagedData, AtomicFile, BlockPageStg, Database, Limits, ObjRef, PageStorage,
+    SharedPagedData, Value, DB,
 };
 
 use std::{
     rc::Rc,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
 /// Program entry point
 fn main() {
@@ -33,10 +37,31 @@ fn main_inner() {
     limits.swbuf = args.swbuf;
     limits.uwbuf = args.uwbuf;
 
+    let metrics = Arc::new(share::Metrics::default());
+
+    // Check the database files can be written.
+    if let Err(e) = check_writable() {
+        let exists = std::path::Path::new("rustweb.rustdb").exists();
+        if args.on_readonly == share::OnReadOnly::Fail || !is_master || !exists {
+            println!("Database files cannot be written: {e} - server not started");
+            std::process::exit(1);
+        }
+        println!("WARNING: database files cannot be written: {e} - server is read-only, updates will be rejected");
+        metrics.read_only.store(true, Ordering::Relaxed);
+    }
+
+    // If the update file is not empty, the last commit was interrupted, it is completed when AtomicFile is constructed.
+    let upd_size = file_size("rustweb.upd");
+    if upd_size > 0 {
+        println!("Update file rustweb.upd has {upd_size} bytes - completing interrupted commit");
+        metrics.upd_recovered.store(true, Ordering::Relaxed);
+    }
+
     // Construct BlockPageStg.
-    let file = MultiFileStorage::new("rustweb.rustdb");
-    let upd = SimpleFileStorage::new("rustweb.upd");
+    let file = storage::RetryFileStorage::new("rustweb.rustdb", args.save_retries, metrics.clone());
+    let upd = storage::RetryFileStorage::new("rustweb.upd", args.save_retries, metrics.clone());
     let stg = AtomicFile::new_with_limits(file, upd, &limits);
+    let stg = storage::CheckpointStorage::new(stg, args.upd_checkpoint << 20, metrics.clone());
     let ps = BlockPageStg::new(stg, &limits);
     let is_new = ps.is_new();
 
@@ -50,29 +75,86 @@ fn main_inner() {
 
     let bmap = Arc::new(builtins::get_bmap());
 
-    // Construct tokio task communication channels.
-    let (update_tx, mut update_rx) = mpsc::channel::<share::UpdateMessage>(1);
-    let (email_tx, email_rx) = mpsc::unbounded_channel::<()>();
-    let (sleep_tx, sleep_rx) = mpsc::unbounded_channel::<u64>();
-    let (wait_tx, _wait_rx) = broadcast::channel::<()>(16);
+    // Set the secret for SIGNTOKEN and VERIFYTOKEN.
+    if let Ok(secret) = std::env::var("RUSTWEB_TOKEN_SECRET") {
+        builtins::set_token_secret(secret.as_bytes());
+    } else if !args.token_secret_file.is_empty() {
+        match std::fs::read(&args.token_secret_file) {
+            Ok(secret) => builtins::set_token_secret(&secret),
+            Err(e) => {
+                println!(
+                    "Token secret file {} cannot be read: {e} - server not started",
+                    args.token_secret_file
+                );
+                std::process::exit(1);
+            }
+        }
+    }
 
-    // Construct shared state.
-    let ss = Arc::new(share::SharedState {
-        spd: spd.clone(),
-        bmap: bmap.clone(),
-        update_tx,
-        email_tx,
-        sleep_tx,
-        wait_tx,
-        is_master,
-        replicate_source: args.rep,
-        replicate_credentials: args.login,
-        dos_limit: [args.dos_count, args.dos_read, args.dos_cpu, args.dos_write],
-        dos: Mutex::new(HashMap::default()),
-        tracetime: args.tracetime,
-        tracedos: args.tracedos,
-        tracemem: args.tracemem,
-    });
+    // Load the TLS certificates.
+    let tls = if args.tls_cert.is_empty() {
+        if !args.tls_sni.is_empty() {
+            println!(
+                "--tls-sni requires --tls-cert ( the default certificate ) - server not started"
+            );
+            std::process::exit(1);
+        }
+        None
+    } else {
+        let key = if args.tls_key.is_empty() {
+            &args.tls_cert
+        } else {
+            &args.tls_key
+        };
+        match tls::config(
+            &args.tls_cert,
+            key,
+            &args.tls_sni,
+            args.tls_min_version,
+            &args.tls_ciphers,
+        ) {
+            Ok(config) => Some(tokio_rustls::TlsAcceptor::from(config)),
+            Err(e) => {
+                println!("TLS configuration error: {e} - server not started");
+                std::process::exit(1);
+            }
+        }
+    };
+
+    // Load the IP geolocation database for GEOIP.
+    if !args.geoip.is_empty() {
+        match geoip::GeoIp::load(&args.geoip) {
+            Ok(g) => builtins::set_geoip(g),
+            Err(e) => println!("GeoIP database {} not loaded: {e}", args.geoip),
+        }
+    }
+
+    let (ss, rx) = shared_state(&args, spd.clone(), bmap.clone(), metrics, is_master);
+    let mut update_rx = rx.update;
+    ss.metrics
+        .db_size_limit
+        .store(ss.max_db_size, Ordering::Relaxed);
+    ss.db_size_update(file_size("rustweb.rustdb"));
+    ss.metrics
+        .upd_size
+        .store(file_size("rustweb.upd"), Ordering::Relaxed);
+
+    // Open the transaction mirror file.
+    let mut mirror = match mirror::Mirror::new(
+        &args.mirror,
+        args.mirror_mode,
+        args.mirror_queue,
+        ss.clone(),
+    ) {
+        Ok(mirror) => mirror,
+        Err(e) => {
+            println!(
+                "Mirror file {} cannot be opened: {e} - server not started",
+                args.mirror
+            );
+            std::process::exit(1);
+        }
+    };
 
     // let rt = tokio::runtime::Runtime::new().unwrap();
     let rt = tokio::runtime::Builder::new_current_thread()
@@ -85,15 +167,36 @@ fn main_inner() {
         if is_master {
             // Start the task that sends emails
             let ssc = ss.clone();
-            tokio::spawn(async move { tasks::email_loop(email_rx, ssc).await });
+            tokio::spawn(async move { tasks::email_loop(rx.email, ssc).await });
+
+            // Start the task that runs jobs from job.Queue
+            let ssc = ss.clone();
+            tokio::spawn(async move { tasks::job_loop(rx.job, ssc).await });
 
             // Start the task that calls timed.Run
             let ssc = ss.clone();
-            tokio::spawn(async move { tasks::sleep_loop(sleep_rx, ssc).await });
+            tokio::spawn(async move { tasks::sleep_loop(rx.sleep, ssc).await });
         } else {
             // Start the database backup task.
             let ssc = ss.clone();
-            tokio::spawn(async move { tasks::backup_loop(is_new, ssc).await });
+            tokio::spawn(async move { tasks::backup_loop(is_new, rx.sync, ssc).await });
+        }
+
+        // Start the task that pushes metrics.
+        if !args.push_url.is_empty() {
+            let instance = if args.push_instance.is_empty() {
+                listen.clone()
+            } else {
+                args.push_instance.clone()
+            };
+            let url = format!(
+                "{}/metrics/job/{}/instance/{}",
+                args.push_url.trim_end_matches('/'),
+                args.push_job,
+                instance
+            );
+            let (interval, ssc) = (args.push_interval.max(1), ss.clone());
+            tokio::spawn(async move { tasks::push_loop(url, interval, ssc).await });
         }
 
         // Start the task that regularly decreases usage values.
@@ -101,6 +204,14 @@ fn main_inner() {
         tokio::spawn(async move { tasks::u_decay_loop(ssc).await });
 
         // Start the task that updates the database.
+        let ssc = ss.clone();
+        let group_commit = args.group_commit.max(1);
+        let (bulk_batch, bulk_wait) = (
+            args.bulk_batch.max(1),
+            Duration::from_millis(args.bulk_wait),
+        );
+        let handle = tokio::runtime::Handle::current();
+        let migrate_backup = args.migrate_backup.clone();
         std::thread::spawn(move || {
             // Get write-access to database ( there will only be one writer ).
             let wapd = AccessPagedData::new_writer(spd);
@@ -115,32 +226,136 @@ fn main_inner() {
                 db.save();
             }
 
-            // Process messages that update the database.
-            while let Some(mut sm) = update_rx.blocking_recv() {
-                let sql = sm.trans.x.qy.sql.clone();
-                db.run(&sql, &mut sm.trans.x);
-                if is_master && !sm.trans.no_log() && db.changed() {
-                    let ser = bincode::serialize(&sm.trans.x.qy).unwrap();
-                    save_transaction(&db, ser);
+            // Create audit table if needed.
+            if ssc.audit != share::Audit::Off
+                && !ssc.metrics.read_only.load(Ordering::Relaxed)
+                && db.get_table(&ObjRef::new("log", "Audit")).is_none()
+            {
+                let mut tr = rustdb::GenTransaction::default();
+                db.run(AUDIT_TABLE, &mut tr);
+                db.save();
+            }
+
+            // Find the schema migrations needed by databases created by earlier versions.
+            let mut migrations = Vec::new();
+            if is_master && !ssc.metrics.read_only.load(Ordering::Relaxed) {
+                // Update log.[/log-get] if it does not support the check parameter ( databases created before /admin-Sync ).
+                let mut tr = rustdb::GenTransaction::default();
+                db.run(LOG_GET_DEF, &mut tr);
+                let def = String::from_utf8_lossy(&tr.rp.output);
+                if !def.is_empty() && !def.contains("'check'") {
+                    migrations.push(("log-get check parameter", LOG_GET));
+                }
+
+                // Add send time column to email.Queue if needed ( databases created before SENDMAILAT ).
+                if let Some(t) = db.get_table(&ObjRef::new("email", "Queue")) {
+                    if t.info.get("at").is_none() {
+                        migrations.push(("email.Queue at column", "ALTER TABLE email.Queue ADD at int"));
+                    }
+                }
+
+                // Create job schema if needed ( databases created before ENQUEUE ).
+                if db.get_table(&ObjRef::new("job", "Queue")).is_none() {
+                    migrations.push(("job schema", init::JOB_SCHEMA));
+                }
+            }
+
+            // Back up the database before the migrations are applied, if the backup fails they are not applied.
+            if !migrations.is_empty() {
+                let names: Vec<&str> = migrations.iter().map(|m| m.0).collect();
+                let mut backup = String::new();
+                if !migrate_backup.is_empty() {
+                    ssc.spd.wait_complete();
+                    match backup_db(&db, "rustweb.rustdb", &migrate_backup, &names) {
+                        Ok(path) => {
+                            println!("Database backed up to {path} before migrations");
+                            backup = path;
+                        }
+                        Err(e) => {
+                            println!("Database backup before migrations failed: {e} - migrations not applied");
+                            migrations.clear();
+                        }
+                    }
+                }
+                for (name, sql) in migrations {
+                    if !migrate(&db, sql, &mut mirror) && !backup.is_empty() {
+                        println!("Migration {name} failed - database backup taken before migrations is {backup}");
+                    }
+                }
+            }
+
+            // Create session table if needed.
+            if ssc.session_store == share::SessionStore::Database
+                && is_master
+                && !ssc.metrics.read_only.load(Ordering::Relaxed)
+                && db.get_table(&ObjRef::new("web", "Session")).is_none()
+            {
+                let mut tr = rustdb::GenTransaction::default();
+                db.run(SESSION_TABLE, &mut tr);
+                db.save();
+            }
+
+            // Process messages that update the database, up to group_commit queued messages are saved together.
+            // In bulk mode, up to bulk_batch messages are saved together, waiting up to bulk_wait for the batch to fill.
+            while let Some(sm) = update_rx.blocking_recv() {
+                let mut batch = vec![sm];
+                let bulk = !ssc.bulk_remaining().is_zero();
+                let (max, wait) = if bulk {
+                    (bulk_batch, bulk_wait)
+                } else {
+                    (group_commit, Duration::ZERO)
+                };
+                let deadline = tokio::time::Instant::now() + wait;
+                while batch.len() < max {
+                    let sm = match update_rx.try_recv() {
+                        Ok(sm) => sm,
+                        Err(mpsc::error::TryRecvError::Empty) if bulk => {
+                            let recv =
+                                async { tokio::time::timeout_at(deadline, update_rx.recv()).await };
+                            let Ok(Some(sm)) = handle.block_on(recv) else {
+                                break;
+                            };
+                            sm
+                        }
+                        Err(_) => break,
+                    };
+                    batch.push(sm);
                 }
-                sm.trans.updates = db.save();
-                let _x = sm.reply.send(sm.trans);
+                save_batch(&db, &ssc, is_master, batch, &mut mirror);
             }
         });
 
         // Process http requests.
         let listener = tokio::net::TcpListener::bind(listen).await.unwrap();
+        let admin_listener = if args.admin_port == 0 {
+            None
+        } else {
+            let listen = format!("{}:{}", args.admin_ip, args.admin_port);
+            Some(tokio::net::TcpListener::bind(listen).await.unwrap())
+        };
+        // Run the startup SQL now the server is ready to accept connections.
+        if is_master && !args.startup_sql.is_empty() {
+            let (list, ssc) = (args.startup_sql.clone(), ss.clone());
+            tokio::spawn(async move { tasks::startup(list, ssc).await });
+        }
+
+        let mut accept_limit = share::TokenBucket::new(args.accept_rate, args.accept_burst);
+        let mut accept_at = None;
         loop {
             tokio::select! {
-                a = listener.accept() =>
+                a = accept(&listener, &mut accept_limit, &mut accept_at, &ss) =>
                 {
-                    let (stream, src) = a.unwrap();
-                    let ssc = ss.clone();
-                    tokio::spawn(async move {
-                        if let Err(x) = request::process(stream, src.ip().to_string(), ssc).await {
-                            println!("End request process error={:?}", x);
-                        }
-                    });
+                    match a {
+                        Ok((stream, src)) => spawn_request(stream, src, ss.clone(), false, tls.clone()),
+                        Err(e) => accept_failed(e).await,
+                    }
+                }
+                a = accept_admin(&admin_listener) =>
+                {
+                    match a {
+                        Ok((stream, src)) => spawn_request(stream, src, ss.clone(), true, None),
+                        Err(e) => accept_failed(e).await,
+                    }
                 }
                 _ = tokio::signal::ctrl_c() =>
                 {
@@ -158,12 +373,452 @@ fn main_inner() {
     });
     // Wait until any outstanding writes are flushed to secondary storage.
     spdc.wait_complete();
+
+    // Reset the update file, unless it holds a commit that was not applied ( after a storage failure ).
+    if !ss.metrics.read_only.load(Ordering::Relaxed) {
+        match storage::cleanup_upd("rustweb.upd") {
+            Ok(0) => {}
+            Ok(n) => println!("Update file rustweb.upd has {n} bytes - the commit is completed at the next startup"),
+            Err(e) => println!("Update file rustweb.upd cleanup failed: {e}"),
+        }
+    }
+}
+
+/// Receivers for tokio task communication channels.
+struct Receivers {
+    update: mpsc::Receiver<share::UpdateMessage>,
+    email: mpsc::UnboundedReceiver<()>,
+    job: mpsc::UnboundedReceiver<()>,
+    sleep: mpsc::UnboundedReceiver<u64>,
+    sync: mpsc::UnboundedReceiver<oneshot::Sender<u64>>,
+}
+
+/// Construct shared state from program arguments.
+fn shared_state(
+    args: &Args,
+    spd: Arc<SharedPagedData>,
+    bmap: Arc<rustdb::BuiltinMap>,
+    metrics: Arc<share::Metrics>,
+    is_master: bool,
+) -> (Arc<share::SharedState>, Receivers) {
+    // Get response body transforms.
+    let transforms = args
+        .transform
+        .iter()
+        .map(|spec| transform::parse(spec).unwrap())
+        .collect();
+
+    // Get request timeouts by path prefix.
+    let route_timeouts = args
+        .route_timeout
+        .iter()
+        .map(|(prefix, secs)| (prefix.clone(), Duration::from_secs(*secs)))
+        .collect();
+
+    // Get trace sampling by status class.
+    let mut trace_status = [None; 6];
+    for (class, n) in &args.trace_status {
+        trace_status[*class] = Some(*n);
+    }
+
+    // Get the favicon to be served without running SQL.
+    let (favicon_path, favicon) = match args.favicon.as_str() {
+        "" => (String::new(), Vec::new()),
+        "none" => (args.favicon_path.clone(), Vec::new()),
+        "default" => (args.favicon_path.clone(), request::default_favicon()),
+        file => (args.favicon_path.clone(), std::fs::read(file).unwrap()),
+    };
+
+    // Construct tokio task communication channels.
+    let (update_tx, update) =
+        mpsc::channel::<share::UpdateMessage>(args.group_commit.max(args.bulk_batch).max(1));
+    let (email_tx, email) = mpsc::unbounded_channel::<()>();
+    let (job_tx, job) = mpsc::unbounded_channel::<()>();
+    let (sleep_tx, sleep) = mpsc::unbounded_channel::<u64>();
+    let (sync_tx, sync) = mpsc::unbounded_channel::<oneshot::Sender<u64>>();
+    let (wait_tx, _wait_rx) = broadcast::channel::<()>(16);
+
+    // Construct shared state.
+    let ss = Arc::new(share::SharedState {
+        spd: spd.clone(),
+        bmap: bmap.clone(),
+        update_tx,
+        email_tx,
+        job_tx,
+        sleep_tx,
+        sync_tx,
+        wait_tx,
+        is_master,
+        replicate_source: args.rep.clone(),
+        replicate_credentials: args.login.clone(),
+        rep_connect_timeout: args.rep_connect_timeout,
+        rep_read_timeout: args.rep_read_timeout,
+        rep_timeout: args.rep_timeout,
+        on_diverge: args.on_diverge,
+        max_replicas: args.max_replicas,
+        dos_limit: [args.dos_count, args.dos_read, args.dos_cpu, args.dos_write],
+        queue_len: AtomicU64::new(0),
+        update_time: AtomicU64::new(0),
+        max_queue: args.max_queue,
+        queue_header: args.queue_header,
+        request_timeout: Duration::from_secs(args.request_timeout),
+        route_timeouts,
+        body_timeout: Duration::from_secs(args.body_timeout),
+        linger: Duration::from_millis(args.linger),
+        dos: Mutex::new(HashMap::default()),
+        bulk_until: AtomicI64::new(0),
+        cache: Mutex::new(cache::Cache::new((args.cache_size << 20) as usize)),
+        session_store: args.session_store,
+        session_ttl: args.session_ttl,
+        session_secure: args.session_secure,
+        dos_rate: args.dos_rate,
+        dos_burst: args.dos_burst,
+        tracetime: args.tracetime,
+        tracedos: args.tracedos,
+        tracemem: args.tracemem,
+        trace_sample: args.trace_sample,
+        trace_slow: args.trace_slow,
+        trace_status,
+        trace_counts: Default::default(),
+        trace_count: AtomicU64::new(0),
+        request_count: AtomicU64::new(0),
+        metrics_enabled: args.metrics,
+        metrics,
+        max_db_size: args.max_db_size << 20,
+        max_result: (args.max_result << 20) as usize,
+        result_mode: args.result_mode,
+        log_append: args.log_append,
+        max_inflate: (args.max_inflate << 20) as usize,
+        max_inflate_ratio: args.max_inflate_ratio,
+        audit: args.audit,
+        mem_mode: args.mem_mode,
+        max_url: args.max_url,
+        favicon_path,
+        favicon,
+        static_dir: args.static_dir.clone(),
+        static_prefix: args.static_prefix.clone(),
+        errors: args.errors,
+        dup_headers: args.dup_headers,
+        obs_fold: args.obs_fold,
+        missing_host: args.missing_host,
+        upgrade: args.upgrade,
+        options: args.options,
+        trailers: args.trailers,
+        allow_trace: args.allow_trace,
+        log_disconnects: args.log_disconnects,
+        gzip: args.gzip,
+        gzip_fast_at: args.gzip_fast_at,
+        gzip_off_at: args.gzip_off_at,
+        transforms,
+        readonly_paths: args.readonly_path.clone(),
+        admin_paths: if args.admin_port == 0 {
+            Vec::new()
+        } else {
+            args.admin_path.clone()
+        },
+    });
+    let rx = Receivers {
+        update,
+        email,
+        job,
+        sleep,
+        sync,
+    };
+    (ss, rx)
+}
+
+/// Accept a connection, waiting first if the accept rate limit is exceeded.
+/// The token taken is kept in at ( the time the token is available ) until a connection is accepted, so it is not lost if the future is dropped.
+async fn accept(
+    listener: &tokio::net::TcpListener,
+    limit: &mut share::TokenBucket,
+    at: &mut Option<tokio::time::Instant>,
+    ss: &share::SharedState,
+) -> std::io::Result<(tokio::net::TcpStream, std::net::SocketAddr)> {
+    let ready = *at.get_or_insert_with(|| {
+        let now = tokio::time::Instant::now();
+        match limit.take() {
+            Some(wait) => {
+                ss.metrics.accept_delayed.fetch_add(1, Ordering::Relaxed);
+                now + wait
+            }
+            None => now,
+        }
+    });
+    tokio::time::sleep_until(ready).await;
+    let result = listener.accept().await;
+    *at = None;
+    ss.metrics.accepted.fetch_add(1, Ordering::Relaxed);
+    result
+}
+
+/// Log accept error and back off briefly ( e.g. if the process has run out of file descriptors ).
+async fn accept_failed(e: std::io::Error) {
+    println!("Accept failed: {e}");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+/// Accept a connection on the admin port ( never completes if there is no admin port ).
+async fn accept_admin(
+    listener: &Option<tokio::net::TcpListener>,
+) -> std::io::Result<(tokio::net::TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Time allowed for the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spawn task to process request, tls is the acceptor if connections use TLS.
+fn spawn_request(
+    stream: tokio::net::TcpStream,
+    src: std::net::SocketAddr,
+    ss: Arc<share::SharedState>,
+    admin: bool,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+) {
+    tokio::spawn(async move {
+        let ip = src.ip().to_string();
+        let result = match tls {
+            None => request::process(stream, ip, ss, admin).await,
+            Some(tls) => {
+                let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream));
+                match handshake.await {
+                    Ok(Ok(stream)) => request::process(stream, ip, ss, admin).await,
+                    _ => {
+                        ss.metrics.tls_failed.fetch_add(1, Ordering::Relaxed);
+                        Ok(())
+                    }
+                }
+            }
+        };
+        if let Err(x) = result {
+            println!("End request process error={:?}", x);
+        }
+    });
+}
+
+/// SQL to create the audit table.
+const AUDIT_TABLE: &str = "CREATE TABLE [log].[Audit]([time] int,[uid] string,[ip] string,[sql] string,[path] string,[args] string,[status] int,[hash] binary)";
+
+/// SQL to create the session table, Expires is seconds since 1970.
+const SESSION_TABLE: &str = "CREATE TABLE [web].[Session]([Sid] string,[Data] string,[Expires] int)
+GO
+CREATE INDEX [BySid] ON [web].[Session]([Sid])";
+
+/// SQL to get the definition of log.[/log-get].
+const LOG_GET_DEF: &str = "DECLARE s int SET s = Id FROM sys.Schema WHERE Name = 'log'
+SELECT Def FROM sys.Function WHERE Schema = s AND Name = '/log-get'";
+
+/// SQL to update log.[/log-get] so that with the check parameter it does not wait for the next transaction.
+const LOG_GET: &str = "ALTER FN [log].[/log-get]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  DECLARE k int SET k = PARSEINT( web.Query('k') )
+
+  DECLARE id int, d binary
+
+  SET id = Id, d = data FROM log.Transaction WHERE Id = k
+
+  IF id = k 
+    SELECT d
+  ELSE IF web.Query('check') = ''
+  BEGIN
+    DECLARE dummy int SET dummy = TRANSWAIT()
+  END
+END";
+
+/// Run schema migration SQL on the master, the migration is logged so replicas apply it as well.
+/// The migration is saved as one transaction, result is false if it failed ( and was rolled back ).
+fn migrate(db: &DB, sql: &str, mirror: &mut mirror::Mirror) -> bool {
+    let mut tr = rustdb::GenTransaction::default();
+    tr.qy.sql = Arc::new(sql.to_string());
+    db.run(sql, &mut tr);
+    let ok = if !tr.rp.err.is_empty() {
+        println!("Migration error: {}", tr.rp.err);
+        false
+    } else if db.changed() && !save_transaction(db, share::log_record(&tr.qy), mirror) {
+        println!("Migration rolled back: transaction mirror write failed");
+        db.err.set(true);
+        false
+    } else {
+        true
+    };
+    db.save();
+    ok
+}
+
+/// SQL to get the next log.Transaction Id.
+const NEXT_TRANSACTION: &str = "DECLARE s int SET s = Id FROM sys.Schema WHERE Name = 'log'
+DECLARE g int SET g = IdGen FROM sys.Table WHERE Schema = s AND Name = 'Transaction'
+SELECT g";
+
+/// Copy database file to directory dir before schema migrations, with a text file recording the schema ( the migrations not yet applied ).
+/// Outstanding writes must be complete. Result is the path of the copy.
+fn backup_db(db: &DB, file: &str, dir: &str, migrations: &[&str]) -> std::io::Result<String> {
+    let mut tr = rustdb::GenTransaction::default();
+    db.run(NEXT_TRANSACTION, &mut tr);
+    let next = String::from_utf8_lossy(&tr.rp.output).to_string();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    std::fs::create_dir_all(dir)?;
+    let path = format!("{dir}/rustweb-{now}.rustdb");
+    std::fs::copy(file, &path)?;
+    std::fs::File::open(&path)?.sync_all()?;
+    let info = format!(
+        "Backup of {file} taken before schema migrations at {now}\nServer version: {}\nMigrations not yet applied: {}\nNext log.Transaction Id: {next}\n",
+        env!("CARGO_PKG_VERSION"),
+        migrations.join(", ")
+    );
+    std::fs::write(format!("{dir}/rustweb-{now}.txt"), info)?;
+    Ok(path)
+}
+
+/// Append audit record to log.Audit table.
+/// The hash column is a BLAKE2b hash of the previous record hash and the other columns.
+fn save_audit(db: &DB, trans: &mut share::Trans) {
+    if let Some(t) = db.get_table(&ObjRef::new("log", "Audit")) {
+        let (uid, ip) = trans.ids();
+        let qy = &trans.x.qy;
+        let mut form = qy.form.clone();
+        for (name, value) in form.iter_mut() {
+            if name.to_lowercase().contains("password") {
+                *value = "*".to_string();
+            }
+        }
+        let args = serde_json::to_string(&(&qy.params, &form)).unwrap();
+        let status = trans.x.rp.status_code as i64;
+
+        let mut row = t.row();
+        row.id = t.alloc_id(db);
+        // The previous record is the one with the highest id ( ids may have gaps ).
+        let last = rustdb::sortedfile::Id { id: row.id as u64 };
+        let prev = match t.file.dsc(db, Box::new(last)).next() {
+            Some((pp, off)) => t.access(&pp.borrow(), off).bin(db, 7),
+            None => Vec::new(),
+        };
+        let content = (&prev, qy.now, &uid, &ip, &*qy.sql, &qy.path, &args, status);
+        let hash = blake2_rfc::blake2b::blake2b(32, &[], &bincode::serialize(&content).unwrap());
+
+        let s = |x: &str| Value::String(Rc::new(x.to_string()));
+        row.values[0] = Value::Int(qy.now);
+        row.values[1] = s(&uid);
+        row.values[2] = s(&ip);
+        row.values[3] = s(&qy.sql);
+        row.values[4] = s(&qy.path);
+        row.values[5] = s(&args);
+        row.values[6] = Value::Int(status);
+        row.values[7] = Value::RcBinary(Rc::new(hash.as_bytes().to_vec()));
+        t.insert(db, &mut row);
+    }
+}
+
+/// Get names ( schema.name ) of tables changed by the current transaction.
+fn changed_tables(db: &DB) -> Vec<String> {
+    let tm = db.tables.borrow();
+    tm.iter()
+        .filter(|(_, t)| t.file.changed() || t.id_gen_dirty.get())
+        .map(|(n, _)| format!("{}.{}", n.schema, n.name))
+        .collect()
+}
+
+/// Run post-commit hooks from web.Hook for changed tables.
+/// Each hook runs in a separate transaction, errors are printed and the hook changes rolled back.
+/// Changes made by hooks do not trigger further hooks.
+fn run_hooks(db: &DB, tables: &[String], now: i64, mirror: &mut mirror::Mirror) {
+    if let Some(t) = db.get_table(&ObjRef::new("web", "Hook")) {
+        let mut list: Vec<String> = Vec::new();
+        for (pp, off) in t.scan(db) {
+            let p = pp.borrow();
+            let a = t.access(&p, off);
+            let (table, sql) = (a.str(db, 0), a.str(db, 1));
+            let matched = table == "*" || tables.iter().any(|x| x.eq_ignore_ascii_case(&table));
+            if matched && !list.contains(&sql) {
+                list.push(sql);
+            }
+        }
+        for sql in list {
+            let mut tr = rustdb::GenTransaction::default();
+            tr.qy.sql = Arc::new(sql);
+            tr.qy.now = now;
+            tr.qy.params.insert("tables".to_string(), tables.join(","));
+            let sql = tr.qy.sql.clone();
+            db.run(&sql, &mut tr);
+            if !tr.rp.err.is_empty() {
+                println!("Post-commit hook error: {}", tr.rp.err);
+            } else if db.changed() && !save_transaction(db, share::log_record(&tr.qy), mirror) {
+                println!("Post-commit hook rolled back: transaction mirror write failed");
+                db.err.set(true);
+            }
+            db.save();
+        }
+    }
 }
 
-/// Append compressed, serialised transaction to log.Transaction table
-fn save_transaction(db: &DB, bytes: Vec<u8>) {
+/// Check the database files can be opened for writing ( they are created if they do not exist ).
+fn check_writable() -> std::io::Result<()> {
+    for name in ["rustweb.rustdb", "rustweb.upd"] {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(name)?;
+    }
+    Ok(())
+}
+
+/// Parse --route-timeout spec prefix=seconds.
+fn parse_route_timeout(spec: &str) -> Result<(String, u64), String> {
+    let (prefix, secs) = spec.split_once('=').ok_or("expected prefix=seconds")?;
+    let secs = secs
+        .parse()
+        .map_err(|_| format!("invalid seconds '{secs}'"))?;
+    Ok((prefix.to_string(), secs))
+}
+
+/// Parse --trace-status spec class=N where class is 1xx to 5xx.
+fn parse_trace_status(spec: &str) -> Result<(usize, u64), String> {
+    let (class, n) = spec
+        .split_once('=')
+        .ok_or("expected class=N e.g. 2xx=100")?;
+    let c = match class.as_bytes() {
+        [c @ b'1'..=b'5', b'x', b'x'] => (c - b'0') as usize,
+        _ => {
+            return Err(format!(
+                "invalid status class '{class}', expected 1xx to 5xx"
+            ))
+        }
+    };
+    let n = n
+        .parse()
+        .map_err(|_| format!("invalid sample count '{n}'"))?;
+    Ok((c, n))
+}
+
+/// Get size of file ( zero if it does not exist ).
+fn file_size(name: &str) -> u64 {
+    std::fs::metadata(name).map_or(0, |m| m.len())
+}
+
+/// Append log record ( compressed, serialised transaction ) to log.Transaction table and mirror.
+/// Result is false if the mirror write failed.
+fn save_transaction(db: &DB, bytes: Vec<u8>, mirror: &mut mirror::Mirror) -> bool {
+    if !mirror.write(std::slice::from_ref(&bytes)) {
+        return false;
+    }
+    log_transaction(db, bytes);
+    true
+}
+
+/// Append log record to log.Transaction table.
+fn log_transaction(db: &DB, bytes: Vec<u8>) {
     if let Some(t) = db.get_table(&ObjRef::new("log", "Transaction")) {
-        let bytes = flate3::deflate(&bytes);
         let bytes = Value::RcBinary(Rc::new(bytes));
         let mut row = t.row();
         row.id = t.alloc_id(db);
@@ -172,6 +827,116 @@ fn save_transaction(db: &DB, bytes: Vec<u8>) {
     }
 }
 
+/// Run a batch of update transactions and save them together ( group commit ).
+/// If a transaction in a batch of more than one is rolled back, the whole batch is rolled back and each transaction is run again on its own.
+fn save_batch(
+    db: &DB,
+    ss: &share::SharedState,
+    is_master: bool,
+    mut batch: Vec<share::UpdateMessage>,
+    mirror: &mut mirror::Mirror,
+) {
+    let start = std::time::Instant::now();
+    let m = &ss.metrics;
+    // Request contexts, to reset the transactions if the batch is rolled back.
+    let ctxs: Vec<_> = if batch.len() > 1 {
+        let ctx = |sm: &mut share::UpdateMessage| sm.trans.with_ctx(|ctx| ctx.clone());
+        batch.iter_mut().map(ctx).collect()
+    } else {
+        Vec::new()
+    };
+    let mut tables: Vec<String> = Vec::new();
+    let mut records = Vec::new();
+    for sm in &mut batch {
+        if m.storage_failed.load(Ordering::Relaxed) {
+            sm.trans.reject(503, "Storage failed");
+            continue;
+        }
+        let sql_start = std::time::Instant::now();
+        let limit = ss.result_limit(&sm.trans.x.qy.path);
+        let ok = sm.trans.run(db, limit);
+        m.update_sql_time.observe(sql_start.elapsed());
+        if !ok {
+            // Result too large, rollback.
+            db.err.set(true);
+        } else if db.changed() && !ss.db_size_ok() {
+            // Database size limit reached, rollback.
+            db.err.set(true);
+            m.db_size_rejected.fetch_add(1, Ordering::Relaxed);
+            sm.trans.reject(507, "Database size limit reached"); // 507 = Insufficient Storage
+        } else if !ss.mem_check() {
+            // Page cache memory hard limit exceeded, rollback.
+            db.err.set(true);
+            sm.trans.reject(503, "Memory limit exceeded");
+        }
+        if db.err.get() && !ctxs.is_empty() {
+            // Rollback the batch, and run each transaction on its own.
+            db.save();
+            m.batch_retries.fetch_add(1, Ordering::Relaxed);
+            for (mut sm, ctx) in batch.into_iter().zip(ctxs) {
+                if let Some(ctx) = ctx {
+                    sm.trans.reset(ctx);
+                }
+                save_batch(db, ss, is_master, vec![sm], mirror);
+            }
+            return;
+        }
+        // Within a batch, a transaction is treated as changing the database if an earlier one did.
+        let changed = db.changed();
+        if is_master && changed {
+            for t in changed_tables(db) {
+                if !tables.contains(&t) {
+                    tables.push(t);
+                }
+            }
+        }
+        if ss.audit == share::Audit::All || ss.audit == share::Audit::Changes && changed {
+            save_audit(db, &mut sm.trans);
+        }
+        if is_master && !sm.trans.no_log() && changed {
+            let record = match sm.trans.log_record.take() {
+                Some(record) => record,
+                None => share::log_record(&sm.trans.x.qy),
+            };
+            records.push(record);
+        }
+    }
+    if !records.is_empty() {
+        if mirror.write(&records) {
+            for record in records {
+                log_transaction(db, record);
+            }
+        } else {
+            // Mirror write failed, rollback.
+            db.err.set(true);
+            for sm in &mut batch {
+                sm.trans.reject(503, "Transaction mirror write failed");
+            }
+        }
+    }
+    let save_start = std::time::Instant::now();
+    let updates = db.save();
+    let save_time = save_start.elapsed();
+    m.save_time.observe(save_time);
+    m.pages_written.fetch_add(updates as u64, Ordering::Relaxed);
+    if updates > 0 {
+        ss.db_size_update(file_size("rustweb.rustdb"));
+        m.upd_size
+            .store(file_size("rustweb.upd"), Ordering::Relaxed);
+    }
+    let now = batch.last().unwrap().trans.x.qy.now;
+    let n = batch.len() as u32;
+    for mut sm in batch {
+        sm.trans.updates = updates;
+        sm.trans.save_time = save_time;
+        let _x = sm.reply.send(sm.trans);
+        ss.update_done(start.elapsed() / n);
+    }
+    if !tables.is_empty() {
+        run_hooks(db, &tables, now, mirror);
+    }
+}
+
 #[cfg(unix)]
 /// Wait for termination signal
 async fn term() {
@@ -189,14 +954,30 @@ async fn term() {
 
 /// Extra SQL builtin functions
 mod builtins;
+/// In-memory key-value cache
+mod cache;
+/// IP geolocation database
+mod geoip;
+/// Response compression
+mod gzip;
 /// SQL initialisation string
 mod init;
+/// Markdown rendering
+mod markdown;
+/// Transaction mirror
+mod mirror;
 /// http request processing
 mod request;
 /// Shared data structures
 mod share;
+/// File storage
+mod storage;
 /// Tasks for email, backup etc
 mod tasks;
+/// TLS configuration
+mod tls;
+/// Response body transforms
+mod transform;
 
 /// Memory allocator ( MiMalloc ).
 #[global_allocator]
@@ -216,6 +997,18 @@ struct Args {
     #[arg(long, value_parser, default_value = "0.0.0.0")]
     ip: String,
 
+    /// Port for admin requests ( paths given by --admin-path ), which are then not served on the main port (0 means no admin port)
+    #[arg(long, value_parser, default_value_t = 0)]
+    admin_port: u16,
+
+    /// Ip Address to listen on for admin requests
+    #[arg(long, value_parser, default_value = "127.0.0.1")]
+    admin_ip: String,
+
+    /// Path prefix for admin requests, served only on the admin port ( may be repeated )
+    #[arg(long, value_parser, default_values_t = ["/admin".to_string(), "/browse-".to_string(), "/metrics".to_string()])]
+    admin_path: Vec<String>,
+
     /// Denial of Service Count Limit
     #[arg(long, value_parser, default_value_t = 1000)]
     dos_count: u64,
@@ -232,10 +1025,262 @@ struct Args {
     #[arg(long, value_parser, default_value_t = 1_000_000)]
     dos_write: u64,
 
+    /// Maximum length of request URL (bytes)
+    #[arg(long, value_parser, default_value_t = 8192)]
+    max_url: usize,
+
+    /// Favicon served without SQL: "" (use SQL), "none" (204 No Content), "default" or a file name
+    #[arg(long, value_parser, default_value = "")]
+    favicon: String,
+
+    /// File containing secret for SIGNTOKEN and VERIFYTOKEN (environment variable RUSTWEB_TOKEN_SECRET takes precedence)
+    #[arg(long, value_parser, default_value = "")]
+    token_secret_file: String,
+
+    /// Amount of data (in MB) written to the database since the last checkpoint after which a commit waits until all committed updates are applied to the database file, bounding the updates recovered from rustweb.upd after a crash (0 means no checkpoints)
+    #[arg(long, value_parser, default_value_t = 64)]
+    upd_checkpoint: u64,
+
+    /// Number of times a storage operation is retried after a transient error (e.g. disk busy)
+    #[arg(long, value_parser, default_value_t = 5)]
+    save_retries: u32,
+
+    /// Maximum number of queued update requests, further requests are rejected with status 503 (0 means no limit)
+    #[arg(long, value_parser, default_value_t = 0)]
+    max_queue: u64,
+
+    /// Add X-Queue-Position and X-Queue-Wait (estimated, in milliseconds) headers to update responses
+    #[arg(long, value_parser, default_value_t = false)]
+    queue_header: bool,
+
+    /// Time in seconds after which a request is abandoned with status 503 (0 means no timeout)
+    #[arg(long, value_parser, default_value_t = 0)]
+    request_timeout: u64,
+
+    /// Request timeout for a path prefix, prefix=seconds e.g. /report/=300 ( may be repeated, first match applies )
+    #[arg(long, value_parser = parse_route_timeout)]
+    route_timeout: Vec<(String, u64)>,
+
+    /// Time in milliseconds to discard unread input before closing a connection, so the client receives the response (0 means close immediately)
+    #[arg(long, value_parser, default_value_t = 1000)]
+    linger: u64,
+
+    /// Prometheus Pushgateway URL to which metrics are pushed, e.g. http://pushgateway:9091
+    #[arg(long, value_parser, default_value = "")]
+    push_url: String,
+
+    /// Interval in seconds between metrics pushes
+    #[arg(long, value_parser, default_value_t = 15)]
+    push_interval: u64,
+
+    /// Job label for pushed metrics
+    #[arg(long, value_parser, default_value = "rustweb")]
+    push_job: String,
+
+    /// Instance label for pushed metrics (default is the listen address)
+    #[arg(long, value_parser, default_value = "")]
+    push_instance: String,
+
+    /// Time in seconds allowed to read a form or multipart request body, after which the status is 408 (0 means no limit)
+    #[arg(long, value_parser, default_value_t = 30)]
+    body_timeout: u64,
+
+    /// IP geolocation database file ( MaxMind DB format ) used by GEOIP
+    #[arg(long, value_parser, default_value = "")]
+    geoip: String,
+
+    /// Send values set by SETTRAILER as trailers after a chunked body, when the request has TE: trailers (otherwise they are sent as headers)
+    #[arg(long, value_parser, default_value_t = false)]
+    trailers: bool,
+
+    /// Size limit in megabytes for the in-memory cache used by CACHESET and CACHEGET (0 disables the cache)
+    #[arg(long, value_parser, default_value_t = 10)]
+    cache_size: u64,
+
+    /// Where session data set by SESSIONSET is stored: memory (in the cache, see --cache-size) or database (web.Session table)
+    #[arg(long, value_enum, default_value_t = share::SessionStore::Memory)]
+    session_store: share::SessionStore,
+
+    /// Session data expires this many seconds after it was last set
+    #[arg(long, value_parser, default_value_t = 86400)]
+    session_ttl: u64,
+
+    /// Add the Secure attribute to the session cookie (use when clients connect using https, e.g. via a proxy)
+    #[arg(long, value_parser, default_value_t = false)]
+    session_secure: bool,
+
+    /// Path of favicon
+    #[arg(long, value_parser, default_value = "/favicon.ico")]
+    favicon_path: String,
+
+    /// Certificate chain file (PEM) for TLS, connections to the main port then use TLS (empty means no TLS)
+    #[arg(long, value_parser, default_value = "")]
+    tls_cert: String,
+
+    /// Private key file (PEM) for --tls-cert (empty means the key is in the certificate file)
+    #[arg(long, value_parser, default_value = "")]
+    tls_key: String,
+
+    /// Certificate for TLS clients requesting a host name (SNI), host=cert,key where host may start with "*." (may be repeated), other clients get --tls-cert
+    #[arg(long, value_parser = tls::parse_sni)]
+    tls_sni: Vec<(String, String, String)>,
+
+    /// Minimum TLS protocol version: 1.2 (TLS 1.2 and 1.3) or 1.3 (TLS 1.3 only)
+    #[arg(long, value_enum, default_value_t = tls::TlsVersion::Tls12)]
+    tls_min_version: tls::TlsVersion,
+
+    /// Allowed TLS cipher suites, comma separated rustls names e.g. TLS13_AES_256_GCM_SHA384 (empty means all, which are all AEAD suites with forward secrecy), the server does not start if none are usable
+    #[arg(long, value_parser, default_value = "")]
+    tls_ciphers: String,
+
+    /// Directory from which static files are served without SQL, a .br or .gz variant is served if the client accepts it (empty means no static files)
+    #[arg(long, value_parser, default_value = "")]
+    static_dir: String,
+
+    /// Path prefix of static files (see --static-dir)
+    #[arg(long, value_parser, default_value = "/static/")]
+    static_prefix: String,
+
+    /// Maximum rate of accepting connections (per second), 0 means no limit
+    #[arg(long, value_parser, default_value_t = 0.0)]
+    accept_rate: f64,
+
+    /// Number of connections that can be accepted in a burst above accept_rate
+    #[arg(long, value_parser, default_value_t = 100.0)]
+    accept_burst: f64,
+
+    /// Serve metrics (Prometheus text format) at /metrics
+    #[arg(long, value_parser, default_value_t = false)]
+    metrics: bool,
+
+    /// Limit on size of database file (in MB), 0 means no limit
+    #[arg(long, value_parser, default_value_t = 0)]
+    max_db_size: u64,
+
+    /// Limit on size of SELECT output for a request (in MB), larger results are rejected with status 507 (or streamed, see --result-mode), 0 means no limit
+    #[arg(long, value_parser, default_value_t = 0)]
+    max_result: u64,
+
+    /// What happens when the SELECT output of a read-only request exceeds --max-result: reject the request, or stream the response while the SQL runs
+    #[arg(long, value_enum, default_value_t = share::ResultMode::Reject)]
+    result_mode: share::ResultMode,
+
+    /// Where log.Transaction records are prepared (serialised and compressed): inline on the write thread, or prepared before the transaction is queued
+    #[arg(long, value_enum, default_value_t = share::LogAppend::Inline)]
+    log_append: share::LogAppend,
+
+    /// Maximum number of queued update transactions that are saved together (group commit), 1 means each transaction is saved on its own
+    #[arg(long, value_parser, default_value_t = 1)]
+    group_commit: usize,
+
+    /// Maximum number of queued update transactions that are saved together in bulk mode (see BULK)
+    #[arg(long, value_parser, default_value_t = 100)]
+    bulk_batch: usize,
+
+    /// Time (in milliseconds) to wait for further update transactions to save together in bulk mode
+    #[arg(long, value_parser, default_value_t = 10)]
+    bulk_wait: u64,
+
+    /// Limit on decompressed size of a gzip request body (in MB), larger bodies are rejected with status 413, 0 means gzip request bodies are not accepted
+    #[arg(long, value_parser, default_value_t = 16)]
+    max_inflate: u64,
+
+    /// Limit on ratio of decompressed to compressed size of a gzip request body, 0 means no limit
+    #[arg(long, value_parser, default_value_t = 100)]
+    max_inflate_ratio: usize,
+
+    /// Transactions to record in log.Audit table
+    #[arg(long, value_enum, default_value_t = share::Audit::Off)]
+    audit: share::Audit,
+
+    /// How SQL errors are reported (as JSON if request Accept header includes application/json)
+    #[arg(long, value_enum, default_value_t = share::Errors::Off)]
+    errors: share::Errors,
+
+    /// Handling of repeated Host, Content-Type and X-Real-IP request headers (differing Content-Length headers are always rejected)
+    #[arg(long, value_enum, default_value_t = share::DupHeaders::Reject)]
+    dup_headers: share::DupHeaders,
+
+    /// Handling of request headers continued on a line starting with a space or tab (obsolete line folding)
+    #[arg(long, value_enum, default_value_t = share::ObsFold::Reject)]
+    obs_fold: share::ObsFold,
+
+    /// Handling of HTTP/1.1 requests without a Host header (HTTP/1.0 requests do not need one)
+    #[arg(long, value_enum, default_value_t = share::MissingHost::Reject)]
+    missing_host: share::MissingHost,
+
+    /// Handling of requests with an Upgrade header (protocol upgrades are not supported)
+    #[arg(long, value_enum, default_value_t = share::Upgrade::Ignore)]
+    upgrade: share::Upgrade,
+
+    /// Handling of OPTIONS * requests (server-wide options, not routed to SQL)
+    #[arg(long, value_enum, default_value_t = share::OptionsStar::Allow)]
+    options: share::OptionsStar,
+
+    /// Respond to TRACE requests (by default TRACE is rejected with 405)
+    #[arg(long, value_parser, default_value_t = false)]
+    allow_trace: bool,
+
+    /// Print a message when a client disconnects while the response is written (by default these are only counted in metrics)
+    #[arg(long, value_parser, default_value_t = false)]
+    log_disconnects: bool,
+
+    /// Compress text, JSON, JavaScript and XML responses of at least 1KB with gzip, if the request Accept-Encoding allows it
+    #[arg(long, value_parser, default_value_t = false)]
+    gzip: bool,
+
+    /// Use fast gzip compression when at least this many requests are being processed (0 means never)
+    #[arg(long, value_parser, default_value_t = 0)]
+    gzip_fast_at: u64,
+
+    /// Do not compress responses when at least this many requests are being processed (0 means never)
+    #[arg(long, value_parser, default_value_t = 0)]
+    gzip_off_at: u64,
+
+    /// SQL run each time the server starts (master only), once it is ready to accept connections, e.g. "EXEC web.Startup()" (may be repeated)
+    #[arg(long, value_parser)]
+    startup_sql: Vec<String>,
+
+    /// Response body transform, content-type=name where name is html or css, e.g. text/html=html ( may be repeated )
+    #[arg(long, value_parser)]
+    transform: Vec<String>,
+
+    /// Path prefix for requests which are always processed using a read-only copy of the database ( may be repeated )
+    #[arg(long, value_parser)]
+    readonly_path: Vec<String>,
+
+    /// Directory where the database file is copied before schema migrations are applied at startup, empty means no backup
+    #[arg(long, value_parser, default_value = "migrate-backup")]
+    migrate_backup: String,
+
+    /// File to which logged transactions are appended, for disaster recovery
+    #[arg(long, value_parser, default_value = "")]
+    mirror: String,
+
+    /// Whether a failed mirror write rolls back the transaction (sync) or is retried (async)
+    #[arg(long, value_enum, default_value_t = share::MirrorMode::Sync)]
+    mirror_mode: share::MirrorMode,
+
+    /// Maximum number of records waiting to be written to the mirror file (async), when full transactions are delayed
+    #[arg(long, value_parser, default_value_t = 1000)]
+    mirror_queue: usize,
+
+    /// Denial of Service sustained request rate per user (per second), 0 means no limit
+    #[arg(long, value_parser, default_value_t = 0.0)]
+    dos_rate: f64,
+
+    /// Denial of Service request burst allowance per user
+    #[arg(long, value_parser, default_value_t = 50.0)]
+    dos_burst: f64,
+
     /// Memory limit for page cache (in MB)
     #[arg(long, value_parser, default_value_t = 100)]
     mem: usize,
 
+    /// Behaviour when page cache cannot be trimmed to memory limit
+    #[arg(long, value_enum, default_value_t = share::MemMode::Soft)]
+    mem_mode: share::MemMode,
+
     /// Server to replicate
     #[arg(long, value_parser, default_value = "")]
     rep: String,
@@ -244,6 +1289,30 @@ struct Args {
     #[arg(long, value_parser, default_value = "")]
     login: String,
 
+    /// Replication connect timeout (seconds)
+    #[arg(long, value_parser, default_value_t = 30)]
+    rep_connect_timeout: u64,
+
+    /// Replication read timeout (seconds), 0 means none. Should exceed 600 as master waits up to 10 minutes for a new transaction
+    #[arg(long, value_parser, default_value_t = 0)]
+    rep_read_timeout: u64,
+
+    /// Replication overall request timeout (seconds)
+    #[arg(long, value_parser, default_value_t = 800)]
+    rep_timeout: u64,
+
+    /// Action at startup when the database files cannot be written: fail (do not start) or read-only (reject updates)
+    #[arg(long, value_enum, default_value_t = share::OnReadOnly::Fail)]
+    on_readonly: share::OnReadOnly,
+
+    /// Maximum number of concurrent replication requests, further requests are rejected with status 503 (0 means no limit)
+    #[arg(long, value_parser, default_value_t = 0)]
+    max_replicas: u64,
+
+    /// Action when the replica transaction log diverges from the master log
+    #[arg(long, value_enum, default_value_t = share::Diverge::Stop)]
+    on_diverge: share::Diverge,
+
     /// Trace query time.
     #[arg(long, value_parser, default_value_t = false)]
     tracetime: bool,
@@ -252,6 +1321,18 @@ struct Args {
     #[arg(long, value_parser, default_value_t = false)]
     tracemem: bool,
 
+    /// Trace only 1 in N requests (errors and slow requests are always traced).
+    #[arg(long, value_parser, default_value_t = 1)]
+    trace_sample: u64,
+
+    /// Always trace requests taking at least this time (µs), 0 means no threshold.
+    #[arg(long, value_parser, default_value_t = 0)]
+    trace_slow: u64,
+
+    /// Trace sampling for a status class, class=N e.g. 2xx=100 traces 1 in 100 2xx responses, 0 means never ( may be repeated )
+    #[arg(long, value_parser = parse_trace_status)]
+    trace_status: Vec<(usize, u64)>,
+
     /// Trace Denial of Service information
     #[arg(long, value_parser, default_value_t = false)]
     tracedos: bool,
@@ -284,3 +1365,132 @@ struct Args {
     #[arg(long, value_parser, default_value_t = 0x100000)]
     uwbuf: usize,
 }
+
+/// Shared state for tests with an in-memory database, args are program arguments following the port.
+#[cfg(test)]
+fn test_state(args: &[&str]) -> Arc<share::SharedState> {
+    let args = Args::parse_from(["rustweb2", "3000"].iter().chain(args));
+    let stg = AtomicFile::new(rustdb::MemFile::new(), rustdb::MemFile::new());
+    let spd = SharedPagedData::new_from_ps(BlockPageStg::new(stg, &Limits::default()));
+    let bmap = Arc::new(builtins::get_bmap());
+    let metrics = Arc::new(share::Metrics::default());
+    shared_state(&args, spd, bmap, metrics, true).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run sql in a batch, result is the response output ( or error ) for each statement.
+    fn run_batch(ss: &Arc<share::SharedState>, db: &DB, sql: &[&str]) -> Vec<String> {
+        let mut batch = Vec::new();
+        let mut replies = Vec::new();
+        for sql in sql {
+            let ctx = share::RequestContext::new(String::new(), std::time::Instant::now());
+            let mut trans = share::Trans::new_with_state(ss.clone(), ctx);
+            trans.x.qy.sql = Arc::new(sql.to_string());
+            let (reply, rx) = oneshot::channel();
+            batch.push(share::UpdateMessage { trans, reply });
+            replies.push(rx);
+        }
+        save_batch(db, ss, true, batch, &mut mirror::Mirror::Off);
+        replies
+            .into_iter()
+            .map(|mut rx| {
+                let rp = rx.try_recv().unwrap().x.rp;
+                if rp.err.is_empty() {
+                    String::from_utf8(rp.output).unwrap()
+                } else {
+                    rp.err
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn group_commit() {
+        let ss = test_state(&["--group-commit", "8"]);
+        let wapd = AccessPagedData::new_writer(ss.spd.clone());
+        let db = Database::new(wapd, "", ss.bmap.clone());
+        let init = "CREATE SCHEMA dbo CREATE TABLE dbo.T(x int)
+            CREATE SCHEMA log CREATE TABLE log.Transaction(data binary)";
+        let mut tr = rustdb::GenTransaction::default();
+        db.run(init, &mut tr);
+        db.save();
+
+        let count = ["SELECT '|' | x FROM dbo.T SELECT '*' FROM log.Transaction"];
+        let r = run_batch(&ss, &db, &["INSERT INTO dbo.T(x) VALUES(1)", "SELECT 'x'"]);
+        assert_eq!(r, ["", "x"]);
+        // Both transactions are logged, as the second follows a change in the same batch.
+        assert_eq!(run_batch(&ss, &db, &count), ["|1**"]);
+
+        // A failed transaction rolls back the batch, the others are run again on their own.
+        let r = run_batch(
+            &ss,
+            &db,
+            &[
+                "INSERT INTO dbo.T(x) VALUES(2) SELECT 'a'",
+                "INSERT INTO dbo.T(x) VALUES(3) THROW 'b'",
+                "INSERT INTO dbo.T(x) VALUES(4) SELECT 'c'",
+            ],
+        );
+        assert_eq!(r[0], "a");
+        assert!(r[1].starts_with("b in batch"));
+        assert_eq!(r[2], "c");
+        assert_eq!(run_batch(&ss, &db, &count), ["|1|2|4****"]);
+        assert_eq!(ss.metrics.batch_retries.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn bulk_mode() {
+        let ss = test_state(&[]);
+        let wapd = AccessPagedData::new_writer(ss.spd.clone());
+        let db = Database::new(wapd, "", ss.bmap.clone());
+        assert!(ss.bulk_remaining().is_zero());
+        let r = run_batch(&ss, &db, &["SELECT BULK(60)"]);
+        assert_eq!(r, ["1"]);
+        let t = ss.bulk_remaining();
+        assert!(t > Duration::from_secs(59) && t <= Duration::from_secs(60));
+        run_batch(&ss, &db, &["SELECT BULK(0)"]);
+        assert!(ss.bulk_remaining().is_zero());
+    }
+
+    #[test]
+    fn migrate_backup() {
+        let ss = test_state(&[]);
+        let wapd = AccessPagedData::new_writer(ss.spd.clone());
+        let db = Database::new(wapd, "", ss.bmap.clone());
+        let init = "CREATE SCHEMA dbo CREATE TABLE dbo.T(x int)
+            CREATE SCHEMA log CREATE TABLE log.Transaction(data binary)";
+        let mut tr = rustdb::GenTransaction::default();
+        db.run(init, &mut tr);
+        db.save();
+
+        let dir = std::env::temp_dir().join(format!("rustweb2-{}-migrate", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("test.rustdb");
+        std::fs::write(&file, b"database").unwrap();
+        let backup = dir.join("backup");
+        let path = backup_db(
+            &db,
+            file.to_str().unwrap(),
+            backup.to_str().unwrap(),
+            &["a", "b"],
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"database");
+        let info = std::fs::read_to_string(path.replace(".rustdb", ".txt")).unwrap();
+        assert!(info.contains("Migrations not yet applied: a, b\n"));
+        assert!(info.contains("Next log.Transaction Id: 1\n"));
+
+        // A failed migration is rolled back, a successful one is logged.
+        let mirror = &mut mirror::Mirror::Off;
+        assert!(!migrate(&db, "CREATE TABLE dbo.U(y int) THROW 'x'", mirror));
+        assert!(db.get_table(&ObjRef::new("dbo", "U")).is_none());
+        assert!(migrate(&db, "CREATE TABLE dbo.U(y int)", mirror));
+        assert!(db.get_table(&ObjRef::new("dbo", "U")).is_some());
+        let r = run_batch(&ss, &db, &["SELECT '*' FROM log.Transaction"]);
+        assert_eq!(r, ["*"]);
+    }
+}
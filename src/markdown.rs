@@ -0,0 +1,37 @@
+use pulldown_cmark::{html, Event, Options, Parser};
+use std::sync::LazyLock;
+
+/// Maximum size of Markdown text that is rendered ( larger text is rendered as escaped plain text ).
+pub const MAX_INPUT: usize = 1 << 20;
+
+/// Sanitiser for untrusted output, only http, https and mailto URLs ( or relative URLs ) are allowed.
+static CLEANER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(|| {
+    let mut b = ammonia::Builder::default();
+    b.url_schemes(["http", "https", "mailto"].into());
+    b
+});
+
+/// Render Markdown text as HTML. Unless trusted, HTML in the text is escaped and the result is sanitised using an allow-list of elements, attributes and URL schemes.
+pub fn to_html(text: &str, trusted: bool) -> String {
+    let mut out = String::with_capacity(text.len() + text.len() / 4);
+    if text.len() > MAX_INPUT {
+        out.push_str("<pre>");
+        html::push_html(&mut out, std::iter::once(Event::Text(text.into())));
+        out.push_str("</pre>\n");
+        return out;
+    }
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let parser = Parser::new_ext(text, options);
+    if trusted {
+        html::push_html(&mut out, parser);
+        out
+    } else {
+        // HTML in the text is rendered as text.
+        let parser = parser.map(|e| match e {
+            Event::Html(s) | Event::InlineHtml(s) => Event::Text(s),
+            e => e,
+        });
+        html::push_html(&mut out, parser);
+        CLEANER.clean(&out).to_string()
+    }
+}
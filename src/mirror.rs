@@ -0,0 +1,138 @@
+use crate::share::{MirrorMode, SharedState};
+use std::io::{Read, Write};
+use std::sync::{atomic::Ordering, Arc};
+
+/// Copy of logged transactions written to a file.
+pub enum Mirror {
+    /// No mirror.
+    Off,
+    /// Transactions are written by the update thread, a failed write causes the transaction to be rolled back.
+    Sync(Writer),
+    /// Transactions are sent to a separate thread to be written, failed writes are retried.
+    Async(std::sync::mpsc::SyncSender<Vec<u8>>, Arc<SharedState>),
+}
+
+impl Mirror {
+    /// Open mirror file ( empty path means no mirror ). For Async, queue is the maximum number of records waiting to be written.
+    pub fn new(
+        path: &str,
+        mode: MirrorMode,
+        queue: usize,
+        ss: Arc<SharedState>,
+    ) -> std::io::Result<Self> {
+        if path.is_empty() {
+            return Ok(Self::Off);
+        }
+        let mut w = Writer::open(path, ss.clone())?;
+        Ok(match mode {
+            MirrorMode::Sync => Self::Sync(w),
+            MirrorMode::Async => {
+                let (tx, rx) = std::sync::mpsc::sync_channel::<Vec<u8>>(queue);
+                std::thread::spawn(move || {
+                    while let Ok(data) = rx.recv() {
+                        while !w.write(std::slice::from_ref(&data)) {
+                            std::thread::sleep(std::time::Duration::from_secs(10));
+                        }
+                        w.ss.metrics.mirror_pending.fetch_sub(1, Ordering::Relaxed);
+                    }
+                });
+                Self::Async(tx, ss)
+            }
+        })
+    }
+
+    /// Write transactions ( compressed, serialised queries ). Result is false if a Sync write failed ( then none are written ).
+    pub fn write(&mut self, records: &[Vec<u8>]) -> bool {
+        match self {
+            Self::Off => true,
+            Self::Sync(w) => w.write(records),
+            Self::Async(tx, ss) => {
+                let m = &ss.metrics;
+                for data in records {
+                    m.mirror_pending.fetch_add(1, Ordering::Relaxed);
+                    if let Err(std::sync::mpsc::TrySendError::Full(data)) =
+                        tx.try_send(data.clone())
+                    {
+                        // The queue is full ( writes are failing or too slow ), wait for space.
+                        if m.mirror_queue_full.fetch_add(1, Ordering::Relaxed) == 0 {
+                            println!("Mirror queue full - transactions are delayed");
+                        }
+                        let _ = tx.send(data);
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+/// Writes records to mirror file. Each record is sequence number, data length ( both 8 byte little-endian ) and data.
+pub struct Writer {
+    file: std::fs::File,
+    /// Length of file ( complete records ).
+    len: u64,
+    /// Sequence number of last record.
+    seq: u64,
+    ss: Arc<SharedState>,
+}
+
+impl Writer {
+    /// Open file, find last complete record and remove anything after it.
+    fn open(path: &str, ss: Arc<SharedState>) -> std::io::Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let (mut len, mut seq) = (0, 0);
+        while len + 16 <= buf.len() {
+            let n = u64::from_le_bytes(buf[len + 8..len + 16].try_into().unwrap()) as usize;
+            if len + 16 + n > buf.len() {
+                break;
+            }
+            seq = u64::from_le_bytes(buf[len..len + 8].try_into().unwrap());
+            len += 16 + n;
+        }
+        file.set_len(len as u64)?;
+        ss.metrics.mirror_position.store(seq, Ordering::Relaxed);
+        Ok(Self {
+            file,
+            len: len as u64,
+            seq,
+            ss,
+        })
+    }
+
+    /// Append records and sync file ( once ). Result is false if the write failed.
+    fn write(&mut self, records: &[Vec<u8>]) -> bool {
+        let mut seq = self.seq;
+        let mut buf = Vec::new();
+        for data in records {
+            seq += 1;
+            buf.extend_from_slice(&seq.to_le_bytes());
+            buf.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            buf.extend_from_slice(data);
+        }
+        let result = self
+            .file
+            .write_all(&buf)
+            .and_then(|_| self.file.sync_data());
+        let m = &self.ss.metrics;
+        match result {
+            Ok(()) => {
+                self.seq = seq;
+                self.len += buf.len() as u64;
+                m.mirror_position.store(seq, Ordering::Relaxed);
+                true
+            }
+            Err(e) => {
+                println!("Mirror write error: {e}");
+                let _ = self.file.set_len(self.len);
+                m.mirror_errors.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+}
@@ -1,19 +1,78 @@
-use crate::share::{Error, SharedState, Trans, UseInfo, U_COUNT, U_CPU, U_READ, U_WRITE};
+use crate::share::{
+    DupHeaders, Error, Errors, MissingHost, ObsFold, OptionsStar, RequestContext, ResultMode,
+    SharedState, StreamPart, Trans, Upgrade, UseInfo, SESSION_COOKIE, U_COUNT, U_CPU, U_READ,
+    U_WRITE,
+};
+use crate::{gzip, transform};
 use rustdb::gentrans::GenQuery;
 use rustdb::BTreeMap;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::{atomic::Ordering, Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Process http request, then close the connection. admin is true for connections to the admin port.
+pub async fn process<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    ip: String,
+    ss: Arc<SharedState>,
+    admin: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ss.metrics.active.fetch_add(1, Ordering::Relaxed);
+    let result = process_stream(&mut stream, ip.clone(), ss.clone(), admin).await;
+    ss.metrics.active.fetch_sub(1, Ordering::Relaxed);
+    linger(stream, ss.linger).await;
+    match result {
+        Err(e) if e.downcast_ref::<Error>().is_some_and(|e| e.code == 499) => {
+            // Client disconnected, this is not a server error.
+            ss.metrics
+                .client_disconnects
+                .fetch_add(1, Ordering::Relaxed);
+            if ss.log_disconnects {
+                println!("Client disconnected during response ip={ip}");
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Close connection. Unread input is discarded for up to the linger time, so that the client
+/// receives the response rather than a connection reset ( e.g. if the request body was not read ).
+async fn linger<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, linger: std::time::Duration) {
+    if linger.is_zero() {
+        return;
+    }
+    if stream.shutdown().await.is_err() {
+        return;
+    }
+    let discard = async {
+        let mut buf = [0; 4096];
+        while let Ok(n) = stream.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    };
+    let _ = tokio::time::timeout(linger, discard).await;
+}
 
 /// Process http request.
-pub async fn process(
-    mut stream: tokio::net::TcpStream,
+async fn process_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     ip: String,
     ss: Arc<SharedState>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (r, mut w) = stream.split();
+    admin: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let start = std::time::Instant::now();
+    let (r, mut w) = tokio::io::split(stream);
     let mut r = Buffer::new(r, ss.clone(), ip);
 
-    let h = Headers::get(&mut r).await;
+    let h = Headers::get(&mut r).await.and_then(|h| {
+        if ss.u_burst(&r.uid) {
+            Ok(h)
+        } else {
+            Err(tmr())
+        }
+    });
 
     let h = match h {
         Ok(h) => h,
@@ -21,19 +80,66 @@ pub async fn process(
             if e.code == 0 {
                 return Ok(());
             }
+            let budget = r.u.limit[U_WRITE];
+            let (hdrs, _) = response(e.code, "", Vec::new());
+            let _ = write(&mut w, &hdrs, budget, &mut r.u.used[U_WRITE]).await;
             return Err(e)?;
         }
     };
 
+    // If there is an admin port, admin paths are only served on it, and only admin paths are served on it.
+    if !ss.admin_paths.is_empty() && admin != ss.admin_paths.iter().any(|p| h.path.starts_with(p)) {
+        let budget = r.u.limit[U_WRITE];
+        let (hdrs, _) = response(404, "", Vec::new());
+        write(&mut w, &hdrs, budget, &mut r.u.used[U_WRITE]).await?;
+        return Ok(());
+    }
+
+    // Limit the number of concurrent replication requests.
+    let _replica = if h.path.starts_with("/log-get") {
+        match ss.replica_start() {
+            Some(g) => Some(g),
+            None => {
+                let budget = r.u.limit[U_WRITE];
+                let (hdrs, _) = response(503, "Retry-After: 60\r\n", Vec::new());
+                write(&mut w, &hdrs, budget, &mut r.u.used[U_WRITE]).await?;
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
+
+    // Requests handled without running SQL.
+    let direct = match direct(&h, &ss) {
+        None if is_static(&h, &ss) => Some(static_file(&h, &ss).await),
+        d => d,
+    };
+    if let Some((hdrs, outp)) = direct {
+        let budget = r.u.limit[U_WRITE];
+        write(&mut w, &hdrs, budget, &mut r.u.used[U_WRITE]).await?;
+        write(&mut w, &outp, budget, &mut r.u.used[U_WRITE]).await?;
+        return Ok(());
+    }
+
     let (hdrs, outp) = {
-        let mut t = Trans::new_with_state(ss.clone(), r.uid.clone());
-        let readonly =
-            h.method == b"GET" && h.args.get("save").is_none() || h.args.get("readonly").is_some();
+        let mut ctx = RequestContext::new(r.uid.clone(), start);
+        ctx.id = ss.request_count.fetch_add(1, Ordering::Relaxed) + 1;
+        ctx.bytes_read = r.total;
+        if let Some(sid) = h.cookies.get(SESSION_COOKIE) {
+            if crate::builtins::is_session_id(sid) {
+                ctx.session = sid.clone();
+            }
+        }
+        let mut t = Trans::new_with_state(ss.clone(), ctx.clone());
+        let readonly = h.method == b"GET" && h.args.get("save").is_none()
+            || h.args.get("readonly").is_some()
+            || ss.readonly_paths.iter().any(|p| h.path.starts_with(p));
 
         t.x.qy.path = h.path;
         t.x.qy.params = h.args;
         t.x.qy.cookies = h.cookies;
-        let (ct, clen) = (&h.content_type, h.content_length);
+        let (ct, clen, ce) = (&h.content_type, h.content_length, h.content_encoding);
 
         // Set limits based on login info etc.
         t.readonly = true;
@@ -46,33 +152,84 @@ pub async fn process(
 
         if ct.is_empty() {
             // No body.
-        } else if ct == b"application/x-www-form-urlencoded" {
-            let clen: usize = clen.parse()?;
-            let bytes = r.read(clen).await?;
-            t.x.qy.form = serde_urlencoded::from_bytes(&bytes)?;
-        } else if is_multipart(ct) {
-            get_multipart(&mut r, &mut t.x.qy).await?;
+        } else if ct == b"application/x-www-form-urlencoded" || is_multipart(ct) {
+            r.deadline = ss.body_deadline();
+            if let Err(e) = read_body(&mut r, &mut t.x.qy, ct, &clen, &ce).await {
+                if e.code != 0 {
+                    let budget = r.u.limit[U_WRITE];
+                    let (hdrs, _) = response(e.code, "", Vec::new());
+                    let _ = write(&mut w, &hdrs, budget, &mut r.u.used[U_WRITE]).await;
+                }
+                return Err(e)?;
+            }
         } else {
             t.x.rp.status_code = 501;
         }
+        t.with_ctx(|c| c.bytes_read = r.total);
         r.read_complete();
 
         if t.x.rp.status_code == 200 {
             t.readonly = readonly;
-            t = ss.process(t).await;
+            t.queue_limited = true;
+            let result = if readonly && ss.result_mode == ResultMode::Stream {
+                let (tx, rx) = tokio::sync::mpsc::channel(4);
+                t.stream = Some(tx);
+                let budget = r.u.limit[U_WRITE];
+                let used = &mut r.u.used[U_WRITE];
+                let (result, streamed) = process_streamed(&ss, t, rx, &mut w, budget, used).await?;
+                if streamed {
+                    // The status and headers have been sent, finish the chunked body.
+                    let mut t = match result {
+                        Ok(t) => t,
+                        Err((code, _)) => return Err(Error { code })?,
+                    };
+                    r.u.used[U_CPU] = t.run_time.as_micros() as u64;
+                    if !t.x.rp.err.is_empty() {
+                        // The response is left incomplete, so the client can tell it failed.
+                        println!("Streamed request error: {}", t.x.rp.err);
+                        return Err(Error { code: 500 })?;
+                    }
+                    let trailers = t.trailers();
+                    let trailers = if ss.trailers && h.te_trailers {
+                        &trailers[..]
+                    } else {
+                        &[]
+                    };
+                    let outp = chunked(t.x.rp.output, trailers);
+                    write(&mut w, &outp, budget, &mut r.u.used[U_WRITE]).await?;
+                    return Ok(());
+                }
+                result
+            } else {
+                process_timeout(&ss, t).await
+            };
+            t = match result {
+                Ok(t) => t,
+                Err((code, msg)) => {
+                    let mut t = Trans::new_with_state(ss.clone(), ctx.clone());
+                    t.reject(code, msg);
+                    t
+                }
+            };
             r.uid = t.uid.clone();
             r.u.used[U_CPU] = t.run_time.as_micros() as u64;
-            if ss.tracetime {
+            let trace = (ss.tracetime || ss.tracemem) && ss.trace_sampled(&t);
+            if ss.tracetime && trace {
+                let (id, read) = t.with_ctx(|c| (c.id, c.bytes_read)).unwrap_or_default();
                 println!(
-                    "run time={}µs updates={} readonly={} path={} args={:?}",
+                    "id={} read={} run time={}µs updates={} save={}µs readonly={} status={} path={} args={:?}",
+                    id,
+                    read,
                     t.run_time.as_micros(),
                     t.updates,
+                    t.save_time.as_micros(),
                     readonly,
+                    t.x.rp.status_code,
                     t.x.qy.path,
                     t.x.qy.params,
                 );
             }
-            if ss.tracemem {
+            if ss.tracemem && trace {
                 let s = ss.spd.stash.lock().unwrap();
                 println!(
                     "stash limit={}K used={}K free={}K pages={} cached={} read={} misses={}",
@@ -86,7 +243,21 @@ pub async fn process(
                 );
             }
         }
-        (header(&t), t.x.rp.output)
+        if !t.x.rp.err.is_empty() && ss.errors != Errors::Off {
+            let json = h.accept.contains("application/json");
+            error_response(&mut t, ss.errors, json);
+        }
+        transform::apply(&ss.transforms, &mut t.x.rp);
+        if h.accept_gzip {
+            crate::gzip::apply(&mut t.x.rp, ss.gzip_level());
+        }
+        let trailers = t.trailers();
+        if ss.trailers && h.te_trailers && !trailers.is_empty() {
+            (header(&t, &trailers), chunked(t.x.rp.output, &trailers))
+        } else {
+            t.x.rp.headers.extend(trailers);
+            (header(&t, &[]), t.x.rp.output)
+        }
     };
 
     let budget = r.u.limit[U_WRITE];
@@ -95,24 +266,395 @@ pub async fn process(
     Ok(())
 }
 
-/// Get response header.
-fn header(t: &Trans) -> Vec<u8> {
+/// Get response header, if there are trailers the body is chunked.
+fn header(t: &Trans, trailers: &[(String, String)]) -> Vec<u8> {
+    let extra = if trailers.is_empty() {
+        format!("Content-Length: {}\r\n", t.x.rp.output.len())
+    } else {
+        let names: Vec<&str> = trailers.iter().map(|(n, _)| n.as_str()).collect();
+        let names = names.join(", ");
+        format!("Trailer: {names}\r\nTransfer-Encoding: chunked\r\n")
+    };
+    head(t.x.rp.status_code, &t.x.rp.headers, &extra)
+}
+
+/// Get chunked body ( a single chunk ) followed by trailers.
+fn chunked(body: Vec<u8>, trailers: &[(String, String)]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(body.len() + 256);
+    if !body.is_empty() {
+        b.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        b.extend_from_slice(&body);
+        b.extend_from_slice(b"\r\n");
+    }
+    b.extend_from_slice(b"0\r\n");
+    for (name, value) in trailers {
+        b.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+    }
+    b.extend_from_slice(b"\r\n");
+    b
+}
+
+/// Methods supported.
+const ALLOW: &str = "GET, POST";
+
+/// Get response for requests that are handled without running SQL.
+fn direct(h: &Headers, ss: &SharedState) -> Option<(Vec<u8>, Vec<u8>)> {
+    let allow = format!("Allow: {ALLOW}\r\n");
+    if h.method == b"CONNECT" {
+        // Not a proxy.
+        Some(response(405, &allow, Vec::new()))
+    } else if h.path == "*" {
+        // Asterisk-form target, only valid for OPTIONS.
+        if h.method != b"OPTIONS" {
+            Some(response(400, "", Vec::new()))
+        } else if ss.options == OptionsStar::Allow {
+            Some(response(204, &allow, Vec::new()))
+        } else {
+            Some(response(405, &allow, Vec::new()))
+        }
+    } else if h.method == b"TRACE" {
+        if ss.allow_trace {
+            Some(response(
+                200,
+                "Content-Type: message/http\r\n",
+                h.trace.clone(),
+            ))
+        } else {
+            Some(response(405, &allow, Vec::new()))
+        }
+    } else if !h.upgrade.is_empty() && ss.upgrade == Upgrade::Reject {
+        // No protocol upgrades are supported.
+        Some(response(501, "", Vec::new()))
+    } else if !ss.favicon_path.is_empty() && h.path == ss.favicon_path {
+        let cc = "Cache-Control: public, max-age=86400\r\n";
+        if ss.favicon.is_empty() {
+            Some(response(204, cc, Vec::new()))
+        } else {
+            let hdrs = format!("Content-Type: image/x-icon\r\n{cc}");
+            Some(response(200, &hdrs, ss.favicon.clone()))
+        }
+    } else if ss.metrics_enabled && h.path == "/metrics" {
+        let ct = "Content-Type: text/plain; version=0.0.4\r\n";
+        Some(response(200, ct, ss.metrics.text().into_bytes()))
+    } else {
+        None
+    }
+}
+
+/// Is request for a static file?
+fn is_static(h: &Headers, ss: &SharedState) -> bool {
+    !ss.static_dir.is_empty() && h.method == b"GET" && h.path.starts_with(&ss.static_prefix)
+}
+
+/// Get response for static file. If the client accepts br or gzip and the file has a .br or .gz variant
+/// which is not older than the file, the variant is sent, otherwise the file is compressed as for other responses.
+async fn static_file(h: &Headers, ss: &SharedState) -> (Vec<u8>, Vec<u8>) {
+    let name = &h.path[ss.static_prefix.len()..];
+    if name
+        .split('/')
+        .any(|x| x.is_empty() || x == "." || x == "..")
+        || name.contains('\\')
+    {
+        return response(404, "", Vec::new());
+    }
+    let path = std::path::Path::new(&ss.static_dir).join(name);
+    let mut codings = Vec::new();
+    if h.accept_br {
+        codings.push(("br", ".br"));
+    }
+    if h.accept_gzip {
+        codings.push(("gzip", ".gz"));
+    }
+    let ct = static_type(name);
+    let file = tokio::task::spawn_blocking(move || read_static(&path, &codings)).await;
+    let Ok(Some((data, coding))) = file else {
+        return response(404, "", Vec::new());
+    };
+    let mut rp = rustdb::GenTransaction::new().rp;
+    rp.output = data;
+    rp.headers
+        .push(("Content-Type".to_string(), ct.to_string()));
+    if coding.is_empty() {
+        if h.accept_gzip {
+            gzip::apply(&mut rp, ss.gzip_level());
+        }
+    } else {
+        rp.headers
+            .push(("Content-Encoding".to_string(), coding.to_string()));
+    }
+    if !rp.headers.iter().any(|(n, _)| n == "Vary") {
+        rp.headers
+            .push(("Vary".to_string(), "Accept-Encoding".to_string()));
+    }
+    let mut hdrs = String::new();
+    for (name, value) in &rp.headers {
+        hdrs.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response(200, &hdrs, rp.output)
+}
+
+/// Read static file or its first precompressed variant ( coding, file extension ) which is not older than the file.
+/// Result is the data and the coding ( empty if not precompressed ), None if the file does not exist.
+fn read_static(
+    path: &std::path::Path,
+    codings: &[(&'static str, &str)],
+) -> Option<(Vec<u8>, &'static str)> {
+    let meta = std::fs::metadata(path).ok().filter(|m| m.is_file())?;
+    let modified = meta.modified().ok()?;
+    for (coding, ext) in codings {
+        let mut variant = path.as_os_str().to_owned();
+        variant.push(ext);
+        let fresh = std::fs::metadata(&variant)
+            .is_ok_and(|m| m.is_file() && m.modified().is_ok_and(|t| t >= modified));
+        if fresh {
+            if let Ok(data) = std::fs::read(&variant) {
+                return Some((data, coding));
+            }
+        }
+    }
+    Some((std::fs::read(path).ok()?, ""))
+}
+
+/// Content type of static file from file extension.
+fn static_type(name: &str) -> &'static str {
+    let ext = name.rsplit_once('.').map_or("", |(_, e)| e);
+    match ext.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Read request body ( form or multipart ).
+async fn read_body(
+    r: &mut Buffer<impl AsyncRead + Unpin>,
+    qy: &mut GenQuery,
+    ct: &[u8],
+    clen: &str,
+    ce: &str,
+) -> Result<(), Error> {
+    let gzip = match ce {
+        "" | "identity" => false,
+        "gzip" | "x-gzip" if r.ss.max_inflate > 0 => true,
+        _ => return Err(Error { code: 415 }), // Unsupported Media Type
+    };
+    if gzip {
+        if clen.is_empty() {
+            return Err(Error { code: 411 }); // Length Required
+        }
+        let clen: usize = clen.parse().map_err(|_| bad())?;
+        let bytes = r.read(clen).await?;
+        let data = match gzip::gunzip(&bytes, r.ss.inflate_limit(clen)) {
+            Ok(data) => data,
+            Err(gzip::InflateError::TooLarge) => {
+                r.ss.metrics
+                    .inflate_rejected
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(Error { code: 413 }); // Content Too Large
+            }
+            Err(gzip::InflateError::Invalid) => return Err(bad()),
+        };
+        if is_multipart(ct) {
+            r.set_inflated(data);
+            get_multipart(r, qy).await
+        } else {
+            qy.form = serde_urlencoded::from_bytes(&data)?;
+            Ok(())
+        }
+    } else if is_multipart(ct) {
+        get_multipart(r, qy).await
+    } else {
+        if clen.is_empty() {
+            return Err(Error { code: 411 }); // Length Required
+        }
+        let clen: usize = clen.parse().map_err(|_| bad())?;
+        let bytes = r.read(clen).await?;
+        qy.form = serde_urlencoded::from_bytes(&bytes)?;
+        Ok(())
+    }
+}
+
+/// Process transaction, result is an error status if the timeout for the path expires ( processing continues in the background ) or the task fails.
+async fn process_timeout(ss: &Arc<SharedState>, t: Trans) -> Result<Trans, (u16, &'static str)> {
+    let timeout = ss.timeout(&t.x.qy.path);
+    if timeout.is_zero() {
+        return Ok(ss.process(t).await);
+    }
+    let ssc = ss.clone();
+    let task = tokio::spawn(async move { ssc.process(t).await });
+    match tokio::time::timeout(timeout, task).await {
+        Ok(Ok(t)) => Ok(t),
+        Ok(Err(e)) => {
+            println!("Request task failed: {e}");
+            Err((500, "Internal Server Error"))
+        }
+        Err(_) => Err((503, "Request timed out")),
+    }
+}
+
+/// Process read-only request, writing the response head and body parts from rx as the output is streamed.
+/// Result is the processed request ( or error ) and whether the response head has been written.
+async fn process_streamed(
+    ss: &Arc<SharedState>,
+    t: Trans,
+    mut rx: tokio::sync::mpsc::Receiver<StreamPart>,
+    w: &mut (impl AsyncWrite + Unpin),
+    budget: u64,
+    used: &mut u64,
+) -> Result<(Result<Trans, (u16, &'static str)>, bool), Error> {
+    let process = process_timeout(ss, t);
+    tokio::pin!(process);
+    let mut result = None;
+    let mut streamed = false;
+    loop {
+        tokio::select! {
+            x = &mut process, if result.is_none() => {
+                let Ok(mut t) = x else {
+                    // Timed out or failed, stop streaming.
+                    return Ok((x, streamed));
+                };
+                // Close the stream ( if the output was not streamed it is still open ).
+                t.stream = None;
+                result = Some(Ok(t));
+            }
+            part = rx.recv() => match part {
+                Some(StreamPart::Head(code, headers)) => {
+                    let head = head(code, &headers, "Transfer-Encoding: chunked\r\n");
+                    write(w, &head, budget, used).await?;
+                    streamed = true;
+                }
+                Some(StreamPart::Body(b)) => {
+                    let mut chunk = format!("{:x}\r\n", b.len()).into_bytes();
+                    chunk.extend_from_slice(&b);
+                    chunk.extend_from_slice(b"\r\n");
+                    write(w, &chunk, budget, used).await?;
+                }
+                None => break,
+            }
+        }
+    }
+    // The stream is closed once the SQL has run.
+    let result = match result {
+        Some(x) => x,
+        None => process.await,
+    };
+    Ok((result, streamed))
+}
+
+/// Get response head: status line, headers, Connection: close and extra ( which must end with CRLF ).
+fn head(code: u16, headers: &[(String, String)], extra: &str) -> Vec<u8> {
     let mut h = Vec::with_capacity(4096);
-    let status_line = format!("HTTP/1.1 {}\r\n", t.x.rp.status_code);
+    let status_line = format!("HTTP/1.1 {code}\r\n");
     h.extend_from_slice(status_line.as_bytes());
-    for (name, value) in &t.x.rp.headers {
+    for (name, value) in headers {
         h.extend_from_slice(name.as_bytes());
         h.push(b':');
         h.extend_from_slice(value.as_bytes());
         h.push(13);
         h.push(10);
     }
-    let clen = t.x.rp.output.len();
-    let x = format!("Content-Length: {clen}\r\n\r\n");
-    h.extend_from_slice(x.as_bytes());
+    h.extend_from_slice(format!("Connection: close\r\n{extra}\r\n").as_bytes());
     h
 }
 
+/// Get response header ( status line, extra headers and Content-Length ) and body.
+fn response(code: u16, headers: &str, body: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+    let clen = if code == 204 {
+        String::new()
+    } else {
+        format!("Content-Length: {}\r\n", body.len())
+    };
+    let h = format!("HTTP/1.1 {code}\r\n{headers}Connection: close\r\n{clen}\r\n");
+    (h.into_bytes(), body)
+}
+
+/// Get the default favicon ( from the web.File row in INITSQL ).
+pub fn default_favicon() -> Vec<u8> {
+    let key = "'/favicon.ico','image/x-icon',0x";
+    let mut result = Vec::new();
+    if let Some(start) = crate::init::INITSQL.find(key) {
+        let hex = &crate::init::INITSQL.as_bytes()[start + key.len()..];
+        for pair in hex.chunks(2) {
+            match std::str::from_utf8(pair).map(|x| u8::from_str_radix(x, 16)) {
+                Ok(Ok(b)) if pair.len() == 2 => result.push(b),
+                _ => break,
+            }
+        }
+    }
+    result
+}
+
+/// SQL error details, parsed from error string "{msg} in {routine} at line {line} column {column}.".
+#[derive(serde::Serialize)]
+struct SqlError {
+    code: u16,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    routine: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+}
+
+impl SqlError {
+    /// Parse error string.
+    fn parse(err: &str) -> Self {
+        let mut result = Self {
+            code: 500,
+            message: err.to_string(),
+            routine: None,
+            line: None,
+            column: None,
+        };
+        let parsed = (|| {
+            let (rest, column) = err.strip_suffix('.')?.rsplit_once(" column ")?;
+            let (rest, line) = rest.rsplit_once(" at line ")?;
+            let (message, routine) = rest.rsplit_once(" in ")?;
+            Some((message, routine, line.parse().ok()?, column.parse().ok()?))
+        })();
+        if let Some((message, routine, line, column)) = parsed {
+            result.message = message.to_string();
+            result.routine = Some(routine.to_string());
+            result.line = Some(line);
+            result.column = Some(column);
+        }
+        result
+    }
+}
+
+/// Replace response with 500 error, as JSON if json is true.
+fn error_response(t: &mut Trans, errors: Errors, json: bool) {
+    let text = match errors {
+        Errors::Detail => t.x.rp.err.clone(),
+        _ => "Internal Server Error".to_string(),
+    };
+    let (ct, body) = if json {
+        let body = serde_json::json!({ "error": SqlError::parse(&text) });
+        ("application/json", body.to_string())
+    } else {
+        ("text/plain;charset=utf-8", text)
+    };
+    t.reject(500, &body);
+    t.x.rp
+        .headers
+        .push(("Content-Type".to_string(), ct.to_string()));
+}
+
 /// Header parsing.
 #[derive(Default)]
 struct Headers {
@@ -120,70 +662,213 @@ struct Headers {
     path: String,
     args: BTreeMap<String, String>,
     host: String,
+    accept: String,
     cookies: BTreeMap<String, String>,
 
     content_type: Vec<u8>,
     content_length: String,
+    /// Content-Encoding header ( lower case ).
+    content_encoding: String,
+    upgrade: String,
+    /// TE header includes trailers.
+    te_trailers: bool,
+    /// Accept-Encoding header allows gzip.
+    accept_gzip: bool,
+    /// Accept-Encoding header allows br.
+    accept_br: bool,
+
+    /// Request echoed in response to TRACE ( sensitive headers are omitted ).
+    trace: Vec<u8>,
+
+    /// Headers seen which should occur only once ( H_xxx bits ).
+    seen: u32,
 }
 
+/// Bits for Headers::seen.
+const H_HOST: u32 = 1;
+const H_CONTENT_TYPE: u32 = 2;
+const H_CONTENT_LENGTH: u32 = 4;
+const H_REAL_IP: u32 = 8;
+const H_TRANSFER_ENCODING: u32 = 16;
+
 impl Headers {
-    async fn get<'a>(br: &mut Buffer<'a>) -> Result<Headers, Error> {
+    async fn get(br: &mut Buffer<impl AsyncRead + Unpin>) -> Result<Headers, Error> {
         let mut r = Self::default();
         br.read_until(b' ', &mut r.method).await?;
         r.method.pop(); // Remove trailing space.
 
         let mut pq = Vec::new();
-        br.read_until(b' ', &mut pq).await?;
+        br.read_until_max(b' ', &mut pq, br.ss.max_url, Error { code: 414 })
+            .await?;
         pq.pop(); // Remove trailing space.
         r.split_pq(&pq)?;
 
         let mut protocol = Vec::new();
         br.read_until(b'\n', &mut protocol).await?;
+        if !protocol.ends_with(b"\r\n") {
+            return Err(bad());
+        }
 
+        let strict = br.ss.dup_headers == DupHeaders::Reject;
+        let is_trace = r.method == b"TRACE";
+        if is_trace {
+            r.trace = [&r.method, &b" "[..], &pq, &b" "[..], &protocol].concat();
+        }
+
+        let fold = br.ss.obs_fold == ObsFold::Allow;
+        // Header line waiting for possible continuation lines ( obs-fold ).
+        let mut pending: Vec<u8> = Vec::new();
         let mut line0 = Vec::new();
         loop {
             let n = br.read_until(b'\n', &mut line0).await?;
-            if n <= 2 {
-                break;
+            // Lines must end with CR LF.
+            if n < 2 || line0[n - 2] != b'\r' {
+                return Err(bad());
             }
             let line = &line0[0..n - 2];
-            if line.len() >= 2 {
-                let b0 = lower(line[0]);
-                let b2 = lower(line[2]);
-                match (b0, b2) {
-                    (b'c', b'o') => {
-                        if let Some(line) = line_is(line, b"cookie") {
-                            r.cookies = cookie_map(line)?;
-                        }
+            if line.first().is_some_and(|b| *b == b' ' || *b == b'\t') {
+                // Obsolete line folding, the line continues the previous header.
+                if !fold || pending.is_empty() {
+                    return Err(bad());
+                }
+                if is_trace && !is_sensitive(&pending) {
+                    r.trace.extend_from_slice(&line0);
+                }
+                pending.push(b' ');
+                pending.extend_from_slice(line.trim_ascii());
+                line0.clear();
+                continue;
+            }
+            if !pending.is_empty() {
+                r.header(br, &pending, strict)?;
+                pending.clear();
+            }
+            if n == 2 {
+                break;
+            }
+            // No white space is allowed between the header name and the colon.
+            match line.iter().position(|b| *b == b':') {
+                Some(i) if i > 0 && line[i - 1] != b' ' && line[i - 1] != b'\t' => {}
+                _ => return Err(bad()),
+            }
+            if is_trace && !is_sensitive(line) {
+                r.trace.extend_from_slice(&line0);
+            }
+            pending.extend_from_slice(line);
+            line0.clear();
+        }
+        // HTTP/1.1 requires a Host header ( which may be empty ).
+        if r.seen & H_HOST == 0
+            && protocol != b"HTTP/1.0\r\n"
+            && br.ss.missing_host == MissingHost::Reject
+        {
+            return Err(bad());
+        }
+        // Transfer-Encoding is not supported for requests, it is an error if Content-Length is also present.
+        if r.seen & H_TRANSFER_ENCODING != 0 {
+            let code = if r.seen & H_CONTENT_LENGTH != 0 {
+                400
+            } else {
+                501
+            };
+            return Err(Error { code });
+        }
+        Ok(r)
+    }
+
+    /// Process header line.
+    fn header(
+        &mut self,
+        br: &mut Buffer<impl AsyncRead + Unpin>,
+        line: &[u8],
+        strict: bool,
+    ) -> Result<(), Error> {
+        if line.len() >= 2 {
+            let b0 = lower(line[0]);
+            let b2 = lower(line[2]);
+            match (b0, b2) {
+                (b'c', b'o') => {
+                    if let Some(line) = line_is(line, b"cookie") {
+                        // Multiple Cookie headers are combined.
+                        self.cookies.extend(cookie_map(line)?);
                     }
-                    (b'c', b'n') => {
-                        if let Some(line) = line_is(line, b"content-type") {
-                            r.content_type = line.to_vec();
-                        } else if let Some(line) = line_is(line, b"content-length") {
-                            r.content_length = tos(line)?;
+                }
+                (b'c', b'n') => {
+                    if let Some(line) = line_is(line, b"content-type") {
+                        self.once(H_CONTENT_TYPE, strict)?;
+                        self.content_type = line.to_vec();
+                    } else if let Some(line) = line_is(line, b"content-length") {
+                        let clen = tos(line)?;
+                        if clen.is_empty() || !clen.bytes().all(|b| b.is_ascii_digit()) {
+                            return Err(bad());
                         }
+                        if self.seen & H_CONTENT_LENGTH != 0 && clen != self.content_length {
+                            return Err(bad());
+                        }
+                        self.seen |= H_CONTENT_LENGTH;
+                        self.content_length = clen;
+                    } else if let Some(line) = line_is(line, b"content-encoding") {
+                        self.content_encoding = tos(line)?.trim().to_ascii_lowercase();
                     }
-                    (b'h', b's') => {
-                        if let Some(line) = line_is(line, b"host") {
-                            r.host = tos(line)?;
+                }
+                (b'a', b'c') => {
+                    if let Some(line) = line_is(line, b"accept") {
+                        // Multiple Accept headers are combined.
+                        if !self.accept.is_empty() {
+                            self.accept.push_str(", ");
                         }
+                        self.accept.push_str(&tos(line)?);
+                    } else if let Some(line) = line_is(line, b"accept-encoding") {
+                        let ae = tos(line)?;
+                        self.accept_gzip |= ae.split(',').any(|x| accepts(x, "gzip"));
+                        self.accept_br |= ae.split(',').any(|x| accepts(x, "br"));
                     }
-                    (b'x', b'r') => {
-                        if let Some(line) = line_is(line, b"x-real-ip") {
-                            let ip = tos(line)?;
-                            br.u.limit = br.ss.u_budget(ip.clone());
-                            br.uid = ip;
-                            if br.u.limit[U_COUNT] == 0 {
-                                return Err(tmr());
-                            }
+                }
+                (b't', b'a') if line_is(line, b"transfer-encoding").is_some() => {
+                    self.seen |= H_TRANSFER_ENCODING;
+                }
+                (b't', b':') => {
+                    if let Some(line) = line_is(line, b"te") {
+                        let te = tos(line)?;
+                        let mut codings = te.split(',').map(|x| x.trim());
+                        self.te_trailers |= codings.any(|x| x.eq_ignore_ascii_case("trailers"));
+                    }
+                }
+                (b'u', b'g') => {
+                    if let Some(line) = line_is(line, b"upgrade") {
+                        self.upgrade = tos(line)?;
+                    }
+                }
+                (b'h', b's') => {
+                    if let Some(line) = line_is(line, b"host") {
+                        self.once(H_HOST, strict)?;
+                        self.host = tos(line)?;
+                    }
+                }
+                (b'x', b'r') => {
+                    if let Some(line) = line_is(line, b"x-real-ip") {
+                        self.once(H_REAL_IP, strict)?;
+                        let ip = tos(line)?;
+                        br.u.limit = br.ss.u_budget(ip.clone());
+                        br.uid = ip;
+                        if br.u.limit[U_COUNT] == 0 {
+                            return Err(tmr());
                         }
                     }
-                    _ => {}
                 }
+                _ => {}
             }
-            line0.clear();
         }
-        Ok(r)
+        Ok(())
+    }
+
+    /// Note header which should occur only once, error if already seen and strict.
+    fn once(&mut self, bit: u32, strict: bool) -> Result<(), Error> {
+        if strict && self.seen & bit != 0 {
+            return Err(bad());
+        }
+        self.seen |= bit;
+        Ok(())
     }
 
     /// Split the path and args by finding '?'.
@@ -208,6 +893,17 @@ impl Headers {
     }
 }
 
+/// Check whether Accept-Encoding item allows coding ( e.g. gzip, gzip;q=0.5 or *, but not gzip;q=0 ).
+fn accepts(item: &str, coding: &str) -> bool {
+    let mut parts = item.split(';').map(|x| x.trim());
+    let name = parts.next().unwrap_or("");
+    let zero = parts.any(|p| {
+        p.strip_prefix("q=")
+            .is_some_and(|q| q.parse::<f64>().is_ok_and(|q| q == 0.0))
+    });
+    (name.eq_ignore_ascii_case(coding) || name == "*") && !zero
+}
+
 /// Check whether current line is named header.
 fn line_is<'a>(line: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
     let n = name.len();
@@ -230,6 +926,17 @@ fn line_is<'a>(line: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
     Some(&line[skip..n])
 }
 
+/// Check whether header should not be echoed by TRACE ( may contain credentials ).
+fn is_sensitive(line: &[u8]) -> bool {
+    let names: [&[u8]; 4] = [
+        b"cookie",
+        b"authorization",
+        b"proxy-authorization",
+        b"x-real-ip",
+    ];
+    names.iter().any(|name| line_is(line, name).is_some())
+}
+
 /// Map upper case char to lower case.
 fn lower(mut b: u8) -> u8 {
     if b.is_ascii_uppercase() {
@@ -258,6 +965,17 @@ fn bad() -> Error {
     Error { code: 400 }
 }
 
+/// Client disconnected ( 499 = Client Closed Request ).
+fn disconnected() -> Error {
+    Error { code: 499 }
+}
+
+/// Check whether error is due to the client closing the connection ( EPIPE, ECONNRESET ).
+fn is_disconnect(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(e.kind(), BrokenPipe | ConnectionReset | ConnectionAborted)
+}
+
 /// Parse cookie header to a map of cookies.
 fn cookie_map(s: &[u8]) -> Result<BTreeMap<String, String>, Error> {
     let mut map = BTreeMap::new();
@@ -339,7 +1057,10 @@ Upload
 use rustdb::Part;
 
 /// Parse multipart body.
-async fn get_multipart<'a>(br: &mut Buffer<'a>, q: &mut GenQuery) -> Result<(), Error> {
+async fn get_multipart(
+    br: &mut Buffer<impl AsyncRead + Unpin>,
+    q: &mut GenQuery,
+) -> Result<(), Error> {
     let mut boundary = Vec::new();
     let n = br.read_until(10, &mut boundary).await?;
     if n < 4 {
@@ -398,9 +1119,9 @@ async fn get_multipart<'a>(br: &mut Buffer<'a>, q: &mut GenQuery) -> Result<(),
 /// Buffer size.
 const BUFFER_SIZE: usize = 2048;
 
-/// Buffer for reading tcp input stream, with budget check.
-struct Buffer<'a> {
-    stream: tokio::net::tcp::ReadHalf<'a>,
+/// Buffer for reading input stream, with budget check.
+struct Buffer<R: AsyncRead + Unpin> {
+    stream: R,
     buf: [u8; BUFFER_SIZE],
     i: usize,
     n: usize,
@@ -409,18 +1130,22 @@ struct Buffer<'a> {
     timer: std::time::SystemTime,
     ss: Arc<SharedState>,
     uid: String,
+    /// Time by which the request body must be read.
+    deadline: Option<tokio::time::Instant>,
+    /// Decompressed request body and position, read instead of the stream.
+    inflated: Option<(Vec<u8>, usize)>,
 }
 
-impl<'a> Drop for Buffer<'a> {
+impl<R: AsyncRead + Unpin> Drop for Buffer<R> {
     fn drop(&mut self) {
         self.read_complete();
         self.ss.u_inc(&self.uid, self.u.used);
     }
 }
 
-impl<'a> Buffer<'a> {
+impl<R: AsyncRead + Unpin> Buffer<R> {
     /// Create a new Buffer.
-    fn new(stream: tokio::net::tcp::ReadHalf<'a>, ss: Arc<SharedState>, uid: String) -> Self {
+    fn new(stream: R, ss: Arc<SharedState>, uid: String) -> Self {
         let limit = ss.u_budget(uid.clone());
         let mut result = Self {
             stream,
@@ -432,6 +1157,8 @@ impl<'a> Buffer<'a> {
             ss,
             u: UseInfo::default(),
             uid,
+            deadline: None,
+            inflated: None,
         };
         result.u.used[U_COUNT] = 1;
         result.u.limit = limit;
@@ -447,9 +1174,26 @@ impl<'a> Buffer<'a> {
         }
     }
 
+    /// Read data ( a decompressed request body ) instead of the stream.
+    fn set_inflated(&mut self, data: Vec<u8>) {
+        self.i = 0;
+        self.n = 0;
+        self.inflated = Some((data, 0));
+    }
+
     /// Fill the buffer. A timeout is set based on the total already read and the buffer size (KB).
     async fn fill(&mut self) -> Result<(), Error> {
         self.i = 0;
+        if let Some((data, pos)) = &mut self.inflated {
+            let n = (data.len() - *pos).min(BUFFER_SIZE);
+            if n == 0 {
+                return Err(eof());
+            }
+            self.buf[..n].copy_from_slice(&data[*pos..*pos + n]);
+            *pos += n;
+            self.n = n;
+            return Ok(());
+        }
         let lim = self.u.limit[U_READ] / ((self.total + BUFFER_SIZE as u64) >> 10);
         let bm = core::time::Duration::from_millis(lim);
         let used = self.timer.elapsed().unwrap();
@@ -457,12 +1201,21 @@ impl<'a> Buffer<'a> {
             return Err(tmr());
         }
         let timeout = bm - used;
+        // The body deadline branch is only enabled if a deadline is set.
+        let has_deadline = self.deadline.is_some();
+        let deadline = self
+            .deadline
+            .unwrap_or_else(|| tokio::time::Instant::now() + timeout);
 
         tokio::select! {
             _ = tokio::time::sleep(timeout) =>
             {
                Err(tmr())?
             }
+            _ = tokio::time::sleep_until(deadline), if has_deadline =>
+            {
+               Err(Error { code: 408 })? // Request Timeout
+            }
             rd = self.stream.read(&mut self.buf) =>
             {
                 match rd
@@ -499,6 +1252,31 @@ impl<'a> Buffer<'a> {
         }
     }
 
+    /// Read until delim is found, returning err if more than max bytes precede delim ( at most max bytes are stored ).
+    async fn read_until_max(
+        &mut self,
+        delim: u8,
+        to: &mut Vec<u8>,
+        max: usize,
+        err: Error,
+    ) -> Result<usize, Error> {
+        let start = to.len();
+        loop {
+            if self.i == self.n {
+                self.fill().await?;
+            }
+            let b = self.buf[self.i];
+            self.i += 1;
+            if b != delim && to.len() - start == max {
+                return Err(err);
+            }
+            to.push(b);
+            if b == delim {
+                return Ok(to.len() - start);
+            }
+        }
+    }
+
     /// Read specified number of bytes.
     async fn read(&mut self, n: usize) -> Result<Vec<u8>, Error> {
         let mut to = Vec::new();
@@ -517,8 +1295,8 @@ impl<'a> Buffer<'a> {
 }
 
 /// Function to write response, with budget-based timeout.
-async fn write<'a>(
-    w: &mut tokio::net::tcp::WriteHalf<'a>,
+async fn write(
+    w: &mut (impl AsyncWrite + Unpin),
     data: &[u8],
     budget: u64,
     used: &mut u64,
@@ -535,7 +1313,9 @@ async fn write<'a>(
                 }
             x = w.write_all(data) =>
                 {
-                    if let Err(_e) = x { result = Err(bad()); }
+                    if let Err(e) = x {
+                        result = Err(if is_disconnect(&e) { disconnected() } else { bad() });
+                    }
                 }
         }
         let elapsed = timer.elapsed().unwrap();
@@ -543,3 +1323,235 @@ async fn write<'a>(
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse request headers, result is the headers or the error status code ( 0 means the input ended ).
+    async fn parse(args: &[&str], req: &str) -> Result<Headers, u16> {
+        let mut br = Buffer::new(req.as_bytes(), crate::test_state(args), "test".to_string());
+        Headers::get(&mut br).await.map_err(|e| e.code)
+    }
+
+    #[tokio::test]
+    async fn valid() {
+        let req = "GET /x/y?a=1&b=two HTTP/1.1\r\nHost: example.com\r\nCookie: c=3; d=4\r\nAccept-Encoding: br, gzip;q=0.5\r\nX-Other:  z\r\n\r\n";
+        let h = parse(&[], req).await.ok().unwrap();
+        assert_eq!(h.method, b"GET");
+        assert_eq!(h.path, "/x/y");
+        assert_eq!(h.args.get("b").unwrap(), "two");
+        assert_eq!(h.host, "example.com");
+        assert_eq!(h.cookies.get("d").unwrap(), "4");
+        assert!(h.accept_gzip);
+        assert!(h.accept_br);
+    }
+
+    #[tokio::test]
+    async fn bare_lf() {
+        let req = "GET / HTTP/1.1\nHost: x\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        let req = "GET / HTTP/1.1\r\nHost: x\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        let req = "GET / HTTP/1.1\r\nHost: x\r\n\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+    }
+
+    #[tokio::test]
+    async fn obs_fold() {
+        let req = "GET / HTTP/1.1\r\nHost: x\r\nAccept: a,\r\n  b\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        let h = parse(&["--obs-fold", "allow"], req).await.ok().unwrap();
+        assert_eq!(h.accept, "a, b");
+        // A continuation line must follow a header line.
+        let req = "GET / HTTP/1.1\r\n x\r\nHost: x\r\n\r\n";
+        assert_eq!(parse(&["--obs-fold", "allow"], req).await.err(), Some(400));
+    }
+
+    #[tokio::test]
+    async fn space_before_colon() {
+        let req = "GET / HTTP/1.1\r\nHost : x\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        let req = "GET / HTTP/1.1\r\nHost: x\r\nAccept\t: y\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        let req = "GET / HTTP/1.1\r\nHost: x\r\nNoColon\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        let req = "GET / HTTP/1.1\r\nHost: x\r\n: y\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+    }
+
+    #[tokio::test]
+    async fn host_required() {
+        let req = "GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        assert!(parse(&["--missing-host", "allow"], req).await.is_ok());
+        let req = "GET / HTTP/1.0\r\n\r\n";
+        assert!(parse(&[], req).await.is_ok());
+        let req = "GET / HTTP/1.1\r\nHost:\r\n\r\n";
+        assert!(parse(&[], req).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn transfer_encoding() {
+        let req = "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(501));
+        let req =
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        let req = "GET / HTTP/1.1\r\nHost: x\r\nTE: trailers, deflate\r\n\r\n";
+        assert!(parse(&[], req).await.ok().unwrap().te_trailers);
+    }
+
+    #[tokio::test]
+    async fn duplicate_headers() {
+        let req = "GET / HTTP/1.1\r\nHost: x\r\nHost: y\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        let h = parse(&["--dup-headers", "last"], req).await.ok().unwrap();
+        assert_eq!(h.host, "y");
+        let req = "POST / HTTP/1.1\r\nHost: x\r\nContent-Type: a\r\nContent-Type: b\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        // Content-Length may be repeated with the same value, even with --dup-headers last.
+        let req = "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(parse(&[], req).await.ok().unwrap().content_length, "5");
+        let req = "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n";
+        assert_eq!(
+            parse(&["--dup-headers", "last"], req).await.err(),
+            Some(400)
+        );
+        let req = "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: -5\r\n\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(400));
+        // Multiple Cookie headers are combined.
+        let req = "GET / HTTP/1.1\r\nHost: x\r\nCookie: a=1\r\nCookie: b=2\r\n\r\n";
+        assert_eq!(parse(&[], req).await.ok().unwrap().cookies.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn url_length() {
+        let req = "GET /12345678 HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert!(parse(&["--max-url", "9"], req).await.is_ok());
+        assert_eq!(parse(&["--max-url", "8"], req).await.err(), Some(414));
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let req = "GET / HTTP/1.1\r\nHost: x\r\n";
+        assert_eq!(parse(&[], req).await.err(), Some(0));
+    }
+
+    /// Read a gzip form body, result is the form or the error status code.
+    async fn gzip_body(args: &[&str], body: &[u8]) -> Result<BTreeMap<String, String>, u16> {
+        let z = gzip::gzip(body, gzip::Level::Default);
+        let ss = crate::test_state(args);
+        let mut br = Buffer::new(&z[..], ss, "test".to_string());
+        let mut qy = rustdb::GenTransaction::new().qy;
+        let (ct, clen) = (b"application/x-www-form-urlencoded", z.len().to_string());
+        read_body(&mut br, &mut qy, ct, &clen, "gzip")
+            .await
+            .map_err(|e| e.code)?;
+        Ok(qy.form)
+    }
+
+    #[tokio::test]
+    async fn gzip_body_limit() {
+        let form = gzip_body(&[], b"a=1&b=2").await.unwrap();
+        assert_eq!(form.get("b").unwrap(), "2");
+
+        // 2MB of a repeated byte compresses to a few KB.
+        let body = [&b"a="[..], &vec![b'x'; 2 << 20]].concat();
+        assert_eq!(
+            gzip_body(&["--max-inflate", "1"], &body).await.err(),
+            Some(413)
+        );
+        let args = ["--max-inflate", "4", "--max-inflate-ratio", "0"];
+        assert_eq!(gzip_body(&args, &body).await.unwrap()["a"].len(), 2 << 20);
+        // The default ratio limit ( 100 ) applies.
+        assert_eq!(
+            gzip_body(&["--max-inflate", "4"], &body).await.err(),
+            Some(413)
+        );
+        // gzip bodies are not accepted with --max-inflate 0.
+        assert_eq!(
+            gzip_body(&["--max-inflate", "0"], b"a=1").await.err(),
+            Some(415)
+        );
+    }
+
+    #[tokio::test]
+    async fn read_until_max() {
+        let ss = crate::test_state(&[]);
+        for (input, max, result) in [
+            ("abc d", 3, Ok(4)),
+            ("abcd e", 3, Err(414)),
+            (" x", 0, Ok(1)),
+            ("a ", 0, Err(414)),
+        ] {
+            let mut br = Buffer::new(input.as_bytes(), ss.clone(), "test".to_string());
+            let mut to = Vec::new();
+            let r = br
+                .read_until_max(b' ', &mut to, max, Error { code: 414 })
+                .await;
+            // At most max bytes are stored, plus the delimiter.
+            let stored = if r.is_ok() { max + 1 } else { max };
+            assert_eq!(r.map_err(|e| e.code), result, "input={input} max={max}");
+            assert!(to.len() <= stored);
+        }
+    }
+
+    /// Get static file with given Accept-Encoding, result is the response header and body.
+    async fn get_static(dir: &std::path::Path, path: &str, ae: &str) -> (String, Vec<u8>) {
+        let args = ["--static-dir", dir.to_str().unwrap(), "--gzip"];
+        let ss = crate::test_state(&args);
+        let req = format!("GET {path} HTTP/1.1\r\nHost: x\r\nAccept-Encoding: {ae}\r\n\r\n");
+        let mut br = Buffer::new(req.as_bytes(), ss.clone(), "test".to_string());
+        let h = Headers::get(&mut br).await.ok().unwrap();
+        assert!(is_static(&h, &ss));
+        let (hdrs, body) = static_file(&h, &ss).await;
+        (String::from_utf8(hdrs).unwrap(), body)
+    }
+
+    #[tokio::test]
+    async fn static_files() {
+        let dir = std::env::temp_dir().join(format!("rustweb2-{}-static", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let css = "p { color: red }\n".repeat(100);
+        std::fs::write(dir.join("a.css"), &css).unwrap();
+        std::fs::write(dir.join("a.css.gz"), b"gz").unwrap();
+        std::fs::write(dir.join("a.css.br"), b"br").unwrap();
+
+        // The preferred precompressed variant the client accepts is sent.
+        let (hdrs, body) = get_static(&dir, "/static/a.css", "gzip, br").await;
+        assert!(hdrs.starts_with("HTTP/1.1 200"));
+        assert!(hdrs.contains("Content-Type: text/css; charset=utf-8\r\n"));
+        assert!(hdrs.contains("Content-Encoding: br\r\n"));
+        assert!(hdrs.contains("Vary: Accept-Encoding\r\n"));
+        assert_eq!(body, b"br");
+        let (hdrs, body) = get_static(&dir, "/static/a.css", "gzip").await;
+        assert!(hdrs.contains("Content-Encoding: gzip\r\n"));
+        assert_eq!(body, b"gz");
+        let (hdrs, body) = get_static(&dir, "/static/a.css", "identity").await;
+        assert!(!hdrs.contains("Content-Encoding"));
+        assert_eq!(body, css.as_bytes());
+
+        // A variant older than the file is not used, the file is compressed instead.
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(60);
+        let gz = std::fs::File::options()
+            .write(true)
+            .open(dir.join("a.css.gz"));
+        gz.unwrap().set_modified(old).unwrap();
+        let (hdrs, body) = get_static(&dir, "/static/a.css", "gzip").await;
+        assert!(hdrs.contains("Content-Encoding: gzip\r\n"));
+        assert_eq!(gzip::gunzip(&body, 1 << 20).ok().unwrap(), css.as_bytes());
+
+        // Missing files and paths outside the directory are not found.
+        for path in [
+            "/static/b.css",
+            "/static/../a.css",
+            "/static//a.css",
+            "/static/",
+        ] {
+            let (hdrs, _) = get_static(&dir, path, "").await;
+            assert!(hdrs.starts_with("HTTP/1.1 404"), "{path}");
+        }
+    }
+}
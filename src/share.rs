@@ -1,6 +1,9 @@
 use rustc_hash::FxHashMap as HashMap;
-use rustdb::{GenTransaction, Transaction};
-use std::sync::{Arc, Mutex};
+use rustdb::{GenQuery, GenTransaction, Transaction};
+use std::sync::{
+    atomic::{AtomicI64, AtomicU64, Ordering},
+    Arc, Mutex,
+};
 use std::time::Duration;
 use tokio::sync::{broadcast, mpsc, oneshot};
 
@@ -18,9 +21,15 @@ pub struct SharedState {
     /// For notifying email loop that emails are in Queue ready to be sent.
     pub email_tx: mpsc::UnboundedSender<()>,
 
+    /// For notifying job loop that jobs are in job.Queue.
+    pub job_tx: mpsc::UnboundedSender<()>,
+
     /// For setting sleep time.
     pub sleep_tx: mpsc::UnboundedSender<u64>,
 
+    /// For requesting an immediate sync on a replica, the reply is the number of transactions saved.
+    pub sync_tx: mpsc::UnboundedSender<oneshot::Sender<u64>>,
+
     /// For notifying tasks waiting for update transaction.
     pub wait_tx: broadcast::Sender<()>,
 
@@ -33,12 +42,72 @@ pub struct SharedState {
     /// Cookies for replication.
     pub replicate_credentials: String,
 
+    /// Replication connect timeout ( seconds ).
+    pub rep_connect_timeout: u64,
+
+    /// Replication read timeout ( seconds, zero means none ).
+    pub rep_read_timeout: u64,
+
+    /// Replication overall request timeout ( seconds ).
+    pub rep_timeout: u64,
+
+    /// Action when the replica transaction log diverges from the master.
+    pub on_diverge: Diverge,
+
+    /// Maximum number of concurrent replication requests ( zero means no limit ).
+    pub max_replicas: u64,
+
     /// Denial of service limits.
     pub dos_limit: UA,
 
+    /// Number of update requests queued or being processed.
+    pub queue_len: AtomicU64,
+
+    /// Average time to process an update request ( micro-seconds ).
+    pub update_time: AtomicU64,
+
+    /// Maximum number of queued update requests, further requests are rejected with status 503 ( zero means no limit ).
+    pub max_queue: u64,
+
+    /// Time bulk mode ends ( micro-seconds since 1970, set by BULK ).
+    pub bulk_until: AtomicI64,
+
+    /// Add X-Queue-Position and X-Queue-Wait headers to update responses.
+    pub queue_header: bool,
+
+    /// Timeout for requests ( zero means no timeout ).
+    pub request_timeout: Duration,
+
+    /// Timeouts for requests by path prefix, the first match applies.
+    pub route_timeouts: Vec<(String, Duration)>,
+
+    /// Time allowed to read a request body ( zero means no limit ).
+    pub body_timeout: Duration,
+
+    /// Time to discard unread input before closing a connection ( zero means close immediately ).
+    pub linger: Duration,
+
     /// Information for mitigating DoS attacks
     pub dos: Mutex<HashMap<String, UseInfo>>,
 
+    /// In-memory key-value cache ( CACHEGET, CACHESET ).
+    pub cache: Mutex<crate::cache::Cache>,
+
+    /// Where session data ( SESSIONGET, SESSIONSET ) is stored.
+    pub session_store: SessionStore,
+
+    /// Session data expires this many seconds after it was last set.
+    pub session_ttl: u64,
+
+    /// Add the Secure attribute to the session cookie.
+    pub session_secure: bool,
+
+    /// Rate at which each user may make requests ( per second, after a burst of dos_burst, zero means no limit ).
+    pub dos_rate: f64,
+
+    /// Number of requests each user may make in a burst.
+    pub dos_burst: f64,
+
     /// Trace time to process each request.
     pub tracetime: bool,
 
@@ -47,6 +116,582 @@ pub struct SharedState {
 
     /// Trace memory
     pub tracemem: bool,
+
+    /// Only trace 1 in trace_sample requests ( errors and slow requests are always traced ).
+    pub trace_sample: u64,
+
+    /// Requests taking at least this time (µs) are always traced ( zero means no threshold ).
+    pub trace_slow: u64,
+
+    /// Count of requests considered for tracing.
+    pub trace_count: AtomicU64,
+
+    /// Count of requests, used to allocate request ids.
+    pub request_count: AtomicU64,
+
+    /// Trace sampling by status class ( index is status / 100 ), overrides trace_sample.
+    pub trace_status: [Option<u64>; 6],
+
+    /// Count of requests considered for tracing by status class.
+    pub trace_counts: [AtomicU64; 6],
+
+    /// Serve metrics at /metrics.
+    pub metrics_enabled: bool,
+
+    /// Server metrics.
+    pub metrics: Arc<Metrics>,
+
+    /// Limit on size of database file in bytes ( zero means no limit ).
+    pub max_db_size: u64,
+
+    /// Limit on size of SELECT output in bytes ( zero means no limit ).
+    pub max_result: usize,
+
+    /// What happens when the SELECT output of a read-only request exceeds max_result.
+    pub result_mode: ResultMode,
+
+    /// Where log.Transaction records are prepared.
+    pub log_append: LogAppend,
+
+    /// Limit on decompressed size of a gzip request body in bytes ( zero means gzip request bodies are not accepted ).
+    pub max_inflate: usize,
+
+    /// Limit on ratio of decompressed to compressed size of a gzip request body ( zero means no limit ).
+    pub max_inflate_ratio: usize,
+
+    /// Which transactions are recorded in log.Audit.
+    pub audit: Audit,
+
+    /// Whether page cache memory limit is soft or hard.
+    pub mem_mode: MemMode,
+
+    /// Maximum length of request target ( URL ).
+    pub max_url: usize,
+
+    /// Path for which favicon is served directly ( empty means handled by SQL ).
+    pub favicon_path: String,
+
+    /// Favicon content ( empty means respond 204 No Content ).
+    pub favicon: Vec<u8>,
+
+    /// Directory from which static files are served ( empty means no static files ).
+    pub static_dir: String,
+
+    /// Path prefix for static files.
+    pub static_prefix: String,
+
+    /// How SQL errors are reported to clients.
+    pub errors: Errors,
+
+    /// Handling of repeated request headers.
+    pub dup_headers: DupHeaders,
+
+    /// Handling of request headers continued using obsolete line folding.
+    pub obs_fold: ObsFold,
+
+    /// Handling of HTTP/1.1 requests without a Host header.
+    pub missing_host: MissingHost,
+
+    /// Handling of requests with an Upgrade header.
+    pub upgrade: Upgrade,
+
+    /// Handling of OPTIONS * requests.
+    pub options: OptionsStar,
+
+    /// Send trailers set by SETTRAILER after a chunked body ( if the request has TE: trailers ).
+    pub trailers: bool,
+
+    /// Respond to TRACE requests by echoing the request ( otherwise 405 ).
+    pub allow_trace: bool,
+
+    /// Print a message when a client disconnects while the response is written.
+    pub log_disconnects: bool,
+
+    /// Compress responses with gzip ( if the client accepts it ).
+    pub gzip: bool,
+
+    /// Use fast compression when this many requests are being processed ( zero means never ).
+    pub gzip_fast_at: u64,
+
+    /// Do not compress when this many requests are being processed ( zero means never ).
+    pub gzip_off_at: u64,
+
+    /// Response body transforms ( content type, transform ).
+    pub transforms: Vec<(String, crate::transform::Transform)>,
+
+    /// Path prefixes for requests processed using a read-only copy of the database.
+    pub readonly_paths: Vec<String>,
+
+    /// Path prefixes only served on the admin port ( empty if there is no admin port ).
+    pub admin_paths: Vec<String>,
+}
+
+/// Which transactions are recorded in log.Audit table.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Audit {
+    /// No transactions.
+    Off,
+    /// Transactions which change the database.
+    Changes,
+    /// All transactions processed by the update task ( read-only requests are not included ).
+    All,
+}
+
+/// Where session data is stored.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SessionStore {
+    /// In-memory cache ( lost when the server restarts ).
+    Memory,
+    /// web.Session table ( only updated by requests that can update the database ).
+    Database,
+}
+
+/// Name of session cookie.
+pub const SESSION_COOKIE: &str = "sid";
+
+/// Behaviour when page cache memory cannot be trimmed to the limit.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MemMode {
+    /// Print a warning, the limit is exceeded.
+    Soft,
+    /// The request fails with status 503 ( and any changes are rolled back ).
+    Hard,
+}
+
+/// How SQL errors ( not handled by SQL code ) are reported to clients.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Errors {
+    /// Not reported, response is whatever SQL output before the error.
+    Off,
+    /// Status 500 with a generic message.
+    Generic,
+    /// Status 500 with the error message and position.
+    Detail,
+}
+
+/// Handling of repeated request headers which should occur only once ( Host, Content-Type, X-Real-IP ).
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DupHeaders {
+    /// Request is rejected with status 400.
+    Reject,
+    /// The last header is used.
+    Last,
+}
+
+/// Handling of request header lines starting with a space or tab ( obsolete line folding ).
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ObsFold {
+    /// Request is rejected with status 400.
+    Reject,
+    /// The line is joined to the previous header line, separated by a space.
+    Allow,
+}
+
+/// Where log.Transaction records are prepared ( serialised and compressed ).
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogAppend {
+    /// On the write thread, after the transaction has run.
+    Inline,
+    /// Before the transaction is queued for the write thread ( wasted if the transaction does not change the database ).
+    Prepared,
+}
+
+/// What happens when the SELECT output of a read-only request exceeds --max-result.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ResultMode {
+    /// Request is rejected with status 507.
+    Reject,
+    /// Response is streamed ( chunked ) while the SQL runs.
+    Stream,
+}
+
+/// Part of a streamed response ( --result-mode stream ).
+pub enum StreamPart {
+    /// Status code and headers, sent when the output first exceeds the limit.
+    Head(u16, Vec<(String, String)>),
+    /// Output.
+    Body(Vec<u8>),
+}
+
+/// Output is sent in parts of about this size once a response is being streamed.
+const STREAM_PART: usize = 1 << 16;
+
+/// Get log.Transaction record for query ( compressed, serialised ).
+pub fn log_record(qy: &GenQuery) -> Vec<u8> {
+    flate3::deflate(&bincode::serialize(qy).unwrap())
+}
+
+/// Handling of HTTP/1.1 requests without a Host header.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MissingHost {
+    /// Request is rejected with status 400.
+    Reject,
+    /// Request is processed.
+    Allow,
+}
+
+/// Handling of requests with an Upgrade header ( no protocol upgrades are supported ).
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Upgrade {
+    /// The Upgrade header is ignored and the request is processed normally.
+    Ignore,
+    /// The request is rejected with status 501.
+    Reject,
+}
+
+/// Handling of OPTIONS * requests ( server-wide options ).
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OptionsStar {
+    /// Status 204 with an Allow header listing the supported methods.
+    Allow,
+    /// The request is rejected with status 405.
+    Reject,
+}
+
+/// Handling of transaction mirror write failures.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MirrorMode {
+    /// Transaction is rolled back ( status 503 ).
+    Sync,
+    /// Transaction is committed, the write is retried every 10 seconds.
+    Async,
+}
+
+/// Action when a replica's transaction log diverges from the master's log.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Diverge {
+    /// Replication stops, no further transactions are saved.
+    Stop,
+    /// A warning is printed and replication continues.
+    Continue,
+}
+
+/// Action at startup when the database files cannot be written.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnReadOnly {
+    /// The server does not start.
+    Fail,
+    /// The server starts in read-only mode, updates are rejected with status 503.
+    ReadOnly,
+}
+
+/// Server metrics, reported in Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    /// Number of connections accepted.
+    pub accepted: AtomicU64,
+    /// Number of connections delayed by accept rate limit.
+    pub accept_delayed: AtomicU64,
+    /// Connections accepted per second ( over last 10 seconds ).
+    pub accept_rate: AtomicU64,
+    /// Value of accepted when accept_rate was last computed.
+    accepted_prev: AtomicU64,
+    /// Size of database file in bytes.
+    pub db_size: AtomicU64,
+    /// Size of update file in bytes ( normally zero, as the file is reset after each commit ).
+    pub upd_size: AtomicU64,
+    /// Update file was not empty at startup, an interrupted commit was completed.
+    pub upd_recovered: std::sync::atomic::AtomicBool,
+    /// Number of checkpoints ( waits for committed updates to be applied to the database file, see --upd-checkpoint ).
+    pub upd_checkpoints: AtomicU64,
+    /// Limit on size of database file in bytes.
+    pub db_size_limit: AtomicU64,
+    /// Number of transactions rejected because database size limit was reached.
+    pub db_size_rejected: AtomicU64,
+    /// Page cache memory used ( after last request ).
+    pub mem_used: AtomicU64,
+    /// Page cache memory limit.
+    pub mem_limit: AtomicU64,
+    /// Number of requests after which page cache could not be trimmed to limit.
+    pub mem_over: AtomicU64,
+    /// Number of page accesses ( after last request ).
+    pub page_reads: AtomicU64,
+    /// Number of page accesses where the page was not already loaded ( after last request ).
+    pub page_misses: AtomicU64,
+    /// Number of pages written by update requests.
+    pub pages_written: AtomicU64,
+    /// Time taken to run SQL for update requests.
+    pub update_sql_time: Histogram,
+    /// Time taken to save update requests ( db.save ).
+    pub save_time: Histogram,
+    /// Number of successful replication requests.
+    pub sync_ok: AtomicU64,
+    /// Number of replication requests that timed out.
+    pub sync_timeout: AtomicU64,
+    /// Number of replication requests that failed ( other than timeout ).
+    pub sync_error: AtomicU64,
+    /// Number of replication requests ( /log-get ) being processed.
+    pub replicas: AtomicU64,
+    /// Number of replication requests rejected because of the replica limit.
+    pub replicas_rejected: AtomicU64,
+    /// Sequence number of last transaction written to mirror file.
+    pub mirror_position: AtomicU64,
+    /// Number of failed mirror file writes.
+    pub mirror_errors: AtomicU64,
+    /// Number of records waiting to be written to mirror file ( async ).
+    pub mirror_pending: AtomicU64,
+    /// Number of times a record was delayed because the mirror queue was full ( async ).
+    pub mirror_queue_full: AtomicU64,
+    /// Number of update batches rolled back and run again one transaction at a time ( group commit ).
+    pub batch_retries: AtomicU64,
+    /// Number of requests being processed.
+    pub active: AtomicU64,
+    /// Number of responses not completed because the client disconnected.
+    pub client_disconnects: AtomicU64,
+    /// Number of TLS connections which failed or timed out during the handshake.
+    pub tls_failed: AtomicU64,
+    /// Number of gzip request bodies rejected because the decompressed size exceeded the limit.
+    pub inflate_rejected: AtomicU64,
+    /// Number of storage operations retried after a transient error.
+    pub storage_retries: AtomicU64,
+    /// A storage write failed, updates are no longer saved.
+    pub storage_failed: std::sync::atomic::AtomicBool,
+    /// Database files cannot be written, server is in read-only mode.
+    pub read_only: std::sync::atomic::AtomicBool,
+    /// Replica transaction log has diverged from the master.
+    pub replica_diverged: std::sync::atomic::AtomicBool,
+    /// Page cache was over limit after last request.
+    mem_was_over: std::sync::atomic::AtomicBool,
+}
+
+impl Metrics {
+    /// Update rates, called every secs seconds.
+    pub fn tick(&self, secs: u64) {
+        let accepted = self.accepted.load(Ordering::Relaxed);
+        let prev = self.accepted_prev.swap(accepted, Ordering::Relaxed);
+        self.accept_rate
+            .store((accepted - prev) / secs, Ordering::Relaxed);
+    }
+
+    /// Get metrics in Prometheus text format.
+    pub fn text(&self) -> String {
+        let mut s = String::new();
+        let get = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        metric(&mut s, "accepted_total", "counter", get(&self.accepted));
+        metric(
+            &mut s,
+            "accept_delayed_total",
+            "counter",
+            get(&self.accept_delayed),
+        );
+        metric(&mut s, "accept_rate", "gauge", get(&self.accept_rate));
+        metric(&mut s, "active_requests", "gauge", get(&self.active));
+        metric(&mut s, "db_size_bytes", "gauge", get(&self.db_size));
+        metric(&mut s, "upd_size_bytes", "gauge", get(&self.upd_size));
+        let recovered = self.upd_recovered.load(Ordering::Relaxed) as u64;
+        metric(&mut s, "upd_recovered", "gauge", recovered);
+        metric(
+            &mut s,
+            "upd_checkpoints_total",
+            "counter",
+            get(&self.upd_checkpoints),
+        );
+        metric(
+            &mut s,
+            "db_size_limit_bytes",
+            "gauge",
+            get(&self.db_size_limit),
+        );
+        metric(
+            &mut s,
+            "db_size_rejected_total",
+            "counter",
+            get(&self.db_size_rejected),
+        );
+        metric(&mut s, "mem_used_bytes", "gauge", get(&self.mem_used));
+        metric(&mut s, "mem_limit_bytes", "gauge", get(&self.mem_limit));
+        metric(&mut s, "mem_over_total", "counter", get(&self.mem_over));
+        metric(&mut s, "page_reads_total", "counter", get(&self.page_reads));
+        metric(
+            &mut s,
+            "page_misses_total",
+            "counter",
+            get(&self.page_misses),
+        );
+        metric(
+            &mut s,
+            "pages_written_total",
+            "counter",
+            get(&self.pages_written),
+        );
+        self.update_sql_time.text(&mut s, "update_sql_seconds");
+        self.save_time.text(&mut s, "save_seconds");
+        metric(&mut s, "sync_ok_total", "counter", get(&self.sync_ok));
+        metric(
+            &mut s,
+            "sync_timeout_total",
+            "counter",
+            get(&self.sync_timeout),
+        );
+        metric(&mut s, "sync_error_total", "counter", get(&self.sync_error));
+        metric(&mut s, "replicas", "gauge", get(&self.replicas));
+        metric(
+            &mut s,
+            "replicas_rejected_total",
+            "counter",
+            get(&self.replicas_rejected),
+        );
+        metric(
+            &mut s,
+            "mirror_position",
+            "gauge",
+            get(&self.mirror_position),
+        );
+        metric(
+            &mut s,
+            "mirror_errors_total",
+            "counter",
+            get(&self.mirror_errors),
+        );
+        metric(&mut s, "mirror_pending", "gauge", get(&self.mirror_pending));
+        metric(
+            &mut s,
+            "mirror_queue_full_total",
+            "counter",
+            get(&self.mirror_queue_full),
+        );
+        metric(
+            &mut s,
+            "batch_retries_total",
+            "counter",
+            get(&self.batch_retries),
+        );
+        metric(
+            &mut s,
+            "client_disconnects_total",
+            "counter",
+            get(&self.client_disconnects),
+        );
+        metric(&mut s, "tls_failed_total", "counter", get(&self.tls_failed));
+        metric(
+            &mut s,
+            "inflate_rejected_total",
+            "counter",
+            get(&self.inflate_rejected),
+        );
+        metric(
+            &mut s,
+            "storage_retries_total",
+            "counter",
+            get(&self.storage_retries),
+        );
+        let failed = self.storage_failed.load(Ordering::Relaxed) as u64;
+        metric(&mut s, "storage_failed", "gauge", failed);
+        let read_only = self.read_only.load(Ordering::Relaxed) as u64;
+        metric(&mut s, "read_only", "gauge", read_only);
+        let diverged = self.replica_diverged.load(Ordering::Relaxed) as u64;
+        metric(&mut s, "replica_diverged", "gauge", diverged);
+        s
+    }
+}
+
+/// Upper bounds of Histogram buckets in microseconds ( there is also a +Inf bucket ).
+const BUCKETS: [u64; 12] = [
+    100, 250, 500, 1000, 2500, 5000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// Histogram of durations, reported as a Prometheus histogram ( in seconds ).
+#[derive(Default)]
+pub struct Histogram {
+    /// Number of observations in each bucket ( not cumulative ).
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    /// Sum of observations in microseconds.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// Record a duration.
+    pub fn observe(&self, t: Duration) {
+        let t = t.as_micros() as u64;
+        let i = BUCKETS
+            .iter()
+            .position(|b| t <= *b)
+            .unwrap_or(BUCKETS.len());
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(t, Ordering::Relaxed);
+    }
+
+    /// Append histogram in Prometheus text format to s.
+    fn text(&self, s: &mut String, name: &str) {
+        use std::fmt::Write;
+        let _ = writeln!(s, "# TYPE rustweb_{name} histogram");
+        let mut total = 0;
+        for (i, c) in self.counts.iter().enumerate() {
+            total += c.load(Ordering::Relaxed);
+            let le = match BUCKETS.get(i) {
+                Some(b) => (*b as f64 / 1e6).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(s, "rustweb_{name}_bucket{{le=\"{le}\"}} {total}");
+        }
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(s, "rustweb_{name}_sum {sum}");
+        let _ = writeln!(s, "rustweb_{name}_count {total}");
+    }
+}
+
+/// Append a metric to s.
+fn metric(s: &mut String, name: &str, kind: &str, value: impl std::fmt::Display) {
+    use std::fmt::Write;
+    let _ = write!(s, "# TYPE rustweb_{name} {kind}\nrustweb_{name} {value}\n");
+}
+
+/// Token bucket rate limiter.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Tokens added per second ( zero means no limit ).
+    rate: f64,
+    /// Maximum number of tokens.
+    burst: f64,
+    /// Tokens available ( negative if tokens are owed ).
+    tokens: f64,
+    /// Time tokens was last updated.
+    last: std::time::Instant,
+}
+
+impl TokenBucket {
+    /// Construct a full bucket.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: std::time::Instant::now(),
+        }
+    }
+
+    /// Take a token if one is available. Result is whether a token was taken.
+    pub fn try_take(&mut self) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+        let now = std::time::Instant::now();
+        let elapsed = (now - self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take a token. Result is how long to wait before the token is available ( None if no wait is needed ).
+    pub fn take(&mut self) -> Option<Duration> {
+        if self.rate <= 0.0 {
+            return None;
+        }
+        let now = std::time::Instant::now();
+        let elapsed = (now - self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst) - 1.0;
+        if self.tokens >= 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(-self.tokens / self.rate))
+        }
+    }
 }
 
 /// Usage array ( total or limit ).
@@ -71,6 +716,8 @@ pub struct UseInfo {
     pub used: UA,
     /// Limits on usage.
     pub limit: UA,
+    /// Limits bursts of requests.
+    pub bucket: Option<TokenBucket>,
 }
 
 impl UseInfo {
@@ -78,6 +725,7 @@ impl UseInfo {
         Self {
             used: [0, 0, 0, 0],
             limit: *limit,
+            bucket: None,
         }
     }
 }
@@ -105,6 +753,20 @@ impl SharedState {
         result
     }
 
+    /// Take a request token for specified user. Result is false if user has exceeded the burst allowance.
+    pub fn u_burst(&self, uid: &str) -> bool {
+        if self.dos_rate <= 0.0 {
+            return true;
+        }
+        let mut m = self.dos.lock().unwrap();
+        let info = m
+            .entry(uid.to_string())
+            .or_insert_with(|| UseInfo::new(&self.dos_limit));
+        info.bucket
+            .get_or_insert_with(|| TokenBucket::new(self.dos_rate, self.dos_burst))
+            .try_take()
+    }
+
     /// Increment usage linits for specified user.
     pub fn u_inc(&self, uid: &str, amount: UA) {
         let mut m = self.dos.lock().unwrap();
@@ -153,6 +815,176 @@ impl SharedState {
         });
     }
 
+    /// Decide whether a request should be traced. Slow requests are always traced, and errors unless --trace-status is given for 5xx.
+    pub fn trace_sampled(&self, t: &Trans) -> bool {
+        if self.trace_slow > 0 && t.run_time.as_micros() as u64 >= self.trace_slow {
+            return true;
+        }
+        // An SQL error is classed as 5xx.
+        let class = match t.x.rp.err.is_empty() {
+            true => (t.x.rp.status_code as usize / 100).min(5),
+            false => 5,
+        };
+        let (sample, count) = match self.trace_status[class] {
+            Some(n) => (n, &self.trace_counts[class]),
+            None if class == 5 => return true,
+            None => (self.trace_sample, &self.trace_count),
+        };
+        if sample == 0 {
+            return false;
+        }
+        let n = count.fetch_add(1, Ordering::Relaxed);
+        sample == 1 || n.is_multiple_of(sample)
+    }
+
+    /// Get deadline for reading a request body which starts now.
+    pub fn body_deadline(&self) -> Option<tokio::time::Instant> {
+        if self.body_timeout.is_zero() {
+            None
+        } else {
+            Some(tokio::time::Instant::now() + self.body_timeout)
+        }
+    }
+
+    /// Get timeout for request path ( zero means no timeout ).
+    pub fn timeout(&self, path: &str) -> Duration {
+        self.route_timeouts
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix))
+            .map_or(self.request_timeout, |(_, t)| *t)
+    }
+
+    /// Start a replication request, result is None if the replica limit has been reached.
+    pub fn replica_start(&self) -> Option<ReplicaGuard<'_>> {
+        let n = self.metrics.replicas.fetch_add(1, Ordering::Relaxed);
+        let guard = ReplicaGuard(&self.metrics.replicas);
+        if self.max_replicas > 0 && n >= self.max_replicas {
+            self.metrics
+                .replicas_rejected
+                .fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(guard)
+    }
+
+    /// Time remaining in bulk mode ( zero if not in bulk mode ).
+    pub fn bulk_remaining(&self) -> Duration {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as i64;
+        let until = self.bulk_until.load(Ordering::Relaxed);
+        Duration::from_micros(until.saturating_sub(now).max(0) as u64)
+    }
+
+    /// Wait until bulk mode ends.
+    pub async fn bulk_wait(&self) {
+        loop {
+            let t = self.bulk_remaining();
+            if t.is_zero() {
+                break;
+            }
+            tokio::time::sleep(t).await;
+        }
+    }
+
+    /// Note update request has been processed, taking time t.
+    pub fn update_done(&self, t: Duration) {
+        self.queue_len.fetch_sub(1, Ordering::Relaxed);
+        let t = t.as_micros() as u64;
+        let avg = self.update_time.load(Ordering::Relaxed);
+        self.update_time.store((avg * 7 + t) / 8, Ordering::Relaxed);
+    }
+
+    /// Get compression level for a response, based on the number of requests being processed.
+    pub fn gzip_level(&self) -> crate::gzip::Level {
+        use crate::gzip::Level;
+        let active = self.metrics.active.load(Ordering::Relaxed);
+        if !self.gzip || self.gzip_off_at > 0 && active >= self.gzip_off_at {
+            Level::Off
+        } else if self.gzip_fast_at > 0 && active >= self.gzip_fast_at {
+            Level::Fast
+        } else {
+            Level::Default
+        }
+    }
+
+    /// Sync replica with master now, result is the number of transactions saved ( or an error message ).
+    async fn rep_sync(&self) -> String {
+        if self.is_master {
+            return "0".to_string();
+        }
+        let (reply, rx) = oneshot::channel::<u64>();
+        let _ = self.sync_tx.send(reply);
+        match rx.await {
+            Ok(n) => n.to_string(),
+            Err(_) => "Replication stopped".to_string(),
+        }
+    }
+
+    /// Get limit on decompressed size of a gzip request body of clen bytes.
+    /// The ratio limit does not apply to bodies that decompress to 64KB or less.
+    pub fn inflate_limit(&self, clen: usize) -> usize {
+        if self.max_inflate_ratio == 0 {
+            self.max_inflate
+        } else {
+            let ratio = clen.saturating_mul(self.max_inflate_ratio).max(1 << 16);
+            self.max_inflate.min(ratio)
+        }
+    }
+
+    /// Get limit on size of SELECT output for path ( replication requests are not limited ).
+    pub fn result_limit(&self, path: &str) -> usize {
+        if path.starts_with("/log-get") {
+            0
+        } else {
+            self.max_result
+        }
+    }
+
+    /// Check database size is below limit.
+    pub fn db_size_ok(&self) -> bool {
+        self.max_db_size == 0 || self.metrics.db_size.load(Ordering::Relaxed) < self.max_db_size
+    }
+
+    /// Update database size metric, with warning when size first reaches 90% of limit.
+    pub fn db_size_update(&self, size: u64) {
+        let old = self.metrics.db_size.swap(size, Ordering::Relaxed);
+        let warn = self.max_db_size / 10 * 9;
+        if self.max_db_size > 0 && size >= warn && old < warn {
+            println!(
+                "Warning: database size={} is approaching limit={}",
+                size, self.max_db_size
+            );
+        }
+    }
+
+    /// Check page cache memory after a request. Result is false if memory mode is hard and the limit was exceeded.
+    pub fn mem_check(&self) -> bool {
+        let (used, limit, read, miss) = {
+            let s = self.spd.stash.lock().unwrap();
+            (s.total.max(0) as u64, s.mem_limit as u64, s.read, s.miss)
+        };
+        let m = &self.metrics;
+        m.mem_used.store(used, Ordering::Relaxed);
+        m.mem_limit.store(limit, Ordering::Relaxed);
+        m.page_reads.store(read, Ordering::Relaxed);
+        m.page_misses.store(miss, Ordering::Relaxed);
+        let over = used > limit;
+        if over {
+            m.mem_over.fetch_add(1, Ordering::Relaxed);
+        }
+        if over && !m.mem_was_over.swap(over, Ordering::Relaxed) {
+            println!(
+                "Warning: page cache used={}K could not be trimmed to limit={}K",
+                used / 1024,
+                limit / 1024
+            );
+        }
+        m.mem_was_over.store(over, Ordering::Relaxed);
+        !over || self.mem_mode == MemMode::Soft
+    }
+
     /// Called to notify tasks waiting for new transaction.
     pub fn new_trans(&self) {
         let _ = self.wait_tx.send(());
@@ -161,22 +993,59 @@ impl SharedState {
     /// Process a server transaction.
     pub async fn process(&self, mut trans: Trans) -> Trans {
         let start = std::time::SystemTime::now();
+        let mut ext = trans.x.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.ctx.readonly = trans.readonly;
+        }
+        trans.x.set_extension(ext);
         let mut trans = if trans.readonly {
             // Readonly request, use read-only copy of database.
             let spd = self.spd.clone();
             let bmap = self.bmap.clone();
+            let limit = self.result_limit(&trans.x.qy.path);
             let task = tokio::task::spawn_blocking(move || {
                 let apd = rustdb::AccessPagedData::new_reader(spd);
                 let db = rustdb::Database::new(apd, "", bmap);
-                let sql = trans.x.qy.sql.clone();
-                db.run(&sql, &mut trans.x);
+                trans.run(&db, limit);
                 trans
             });
-            task.await.unwrap()
+            let mut trans = task.await.unwrap();
+            // A streamed response cannot be rejected, most of it has been sent.
+            if !self.mem_check() && !trans.streamed {
+                trans.reject(503, "Memory limit exceeded");
+            }
+            trans
+        } else if self.metrics.read_only.load(Ordering::Relaxed) {
+            trans.reject(503, "Database is read-only");
+            trans
         } else {
-            let (reply, rx) = oneshot::channel::<Trans>();
-            let _ = self.update_tx.send(UpdateMessage { trans, reply }).await;
-            rx.await.unwrap()
+            // Count the request as queued before checking the limit, so concurrent requests cannot exceed it.
+            let ahead = self.queue_len.fetch_add(1, Ordering::Relaxed);
+            if trans.queue_limited && self.max_queue > 0 && ahead >= self.max_queue {
+                self.queue_len.fetch_sub(1, Ordering::Relaxed);
+                trans.reject(503, "Too many queued updates");
+                trans
+            } else {
+                if self.is_master && self.log_append == LogAppend::Prepared {
+                    // Compress the log record before queueing, so the write thread only has to insert it.
+                    trans = tokio::task::spawn_blocking(move || {
+                        trans.log_record = Some(log_record(&trans.x.qy));
+                        trans
+                    })
+                    .await
+                    .unwrap();
+                }
+                let wait = ahead * self.update_time.load(Ordering::Relaxed) / 1000;
+                let (reply, rx) = oneshot::channel::<Trans>();
+                let _ = self.update_tx.send(UpdateMessage { trans, reply }).await;
+                let mut trans = rx.await.unwrap();
+                if self.queue_header {
+                    let h = &mut trans.x.rp.headers;
+                    h.push(("X-Queue-Position".to_string(), ahead.to_string()));
+                    h.push(("X-Queue-Wait".to_string(), wait.to_string()));
+                }
+                trans
+            }
         };
         if trans.updates > 0 {
             self.new_trans();
@@ -185,7 +1054,7 @@ impl SharedState {
 
         let ext = trans.x.get_extension();
         if let Some(ext) = ext.downcast_ref::<TransExt>() {
-            trans.uid = ext.uid.clone();
+            trans.uid = ext.ctx.uid.clone();
             if self.is_master {
                 if ext.sleep > 0 {
                     let _ = self.sleep_tx.send(ext.sleep);
@@ -193,6 +1062,9 @@ impl SharedState {
                 if ext.tx_email {
                     let _ = self.email_tx.send(());
                 }
+                if ext.tx_job {
+                    let _ = self.job_tx.send(());
+                }
             }
             if ext.trans_wait {
                 let mut wait_rx = self.wait_tx.subscribe();
@@ -201,6 +1073,10 @@ impl SharedState {
                    _ = tokio::time::sleep(Duration::from_secs(600)) => {}
                 }
             }
+            if ext.rep_sync {
+                let result = self.rep_sync().await;
+                trans.x.rp.output.extend_from_slice(result.as_bytes());
+            }
             if ext.trans_flush {
                 let spd = self.spd.clone();
                 let _ = tokio::task::spawn_blocking(move || {
@@ -208,7 +1084,7 @@ impl SharedState {
                 })
                 .await;
             }
-            if ext.to_pdf {
+            if ext.to_pdf && !trans.streamed {
                 trans.convert_to_pdf();
             }
         }
@@ -217,6 +1093,101 @@ impl SharedState {
     }
 }
 
+/// Transaction which limits the size of SELECT output, once the limit is exceeded the output is discarded.
+struct LimitedTrans {
+    x: GenTransaction,
+    limit: usize,
+    over: bool,
+    /// Output over the limit is sent here instead of being rejected.
+    stream: Option<mpsc::Sender<StreamPart>>,
+    /// Output is being streamed.
+    streaming: bool,
+}
+
+impl LimitedTrans {
+    /// Send status, headers and output so far to the stream. Result is false if the response is no longer wanted.
+    fn send(&mut self) -> bool {
+        let Some(tx) = &self.stream else {
+            return false;
+        };
+        if !self.streaming {
+            self.streaming = true;
+            let head = StreamPart::Head(self.x.rp.status_code, self.x.rp.headers.clone());
+            if tx.blocking_send(head).is_err() {
+                return false;
+            }
+        }
+        let body = std::mem::take(&mut self.x.rp.output);
+        tx.blocking_send(StreamPart::Body(body)).is_ok()
+    }
+}
+
+impl Transaction for LimitedTrans {
+    fn status_code(&mut self, code: i64) {
+        self.x.status_code(code)
+    }
+
+    fn header(&mut self, name: &str, value: &str) {
+        self.x.header(name, value)
+    }
+
+    fn selected(&mut self, values: &[rustdb::Value]) {
+        if !self.over {
+            self.x.selected(values);
+            let limit = if self.streaming {
+                STREAM_PART
+            } else {
+                self.limit
+            };
+            if self.x.rp.output.len() > limit && !self.send() {
+                self.over = true;
+                self.x.rp.output = Vec::new();
+            }
+        }
+    }
+
+    fn global(&self, kind: i64) -> i64 {
+        self.x.global(kind)
+    }
+
+    fn arg(&mut self, kind: i64, name: &str) -> std::rc::Rc<String> {
+        self.x.arg(kind, name)
+    }
+
+    fn file_attr(&mut self, fnum: i64, atx: i64) -> std::rc::Rc<String> {
+        self.x.file_attr(fnum, atx)
+    }
+
+    fn file_content(&mut self, fnum: i64) -> Arc<Vec<u8>> {
+        self.x.file_content(fnum)
+    }
+
+    fn set_error(&mut self, err: String) {
+        self.x.set_error(err)
+    }
+
+    fn get_error(&mut self) -> String {
+        self.x.get_error()
+    }
+
+    fn set_extension(&mut self, ext: Box<dyn std::any::Any + Send + Sync>) {
+        self.x.set_extension(ext)
+    }
+
+    fn get_extension(&mut self) -> Box<dyn std::any::Any + Send + Sync> {
+        self.x.get_extension()
+    }
+}
+
+/// Decrements the count of replication requests when dropped.
+pub struct ReplicaGuard<'a>(&'a AtomicU64);
+
+impl Drop for ReplicaGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 /// Transaction to be processed.
 pub struct Trans {
     pub x: GenTransaction,
@@ -224,7 +1195,17 @@ pub struct Trans {
     pub readonly: bool,
     pub run_time: core::time::Duration,
     pub updates: usize,
+    /// Time taken by db.save ( update requests only ).
+    pub save_time: core::time::Duration,
+    /// Log record prepared before the transaction is queued ( --log-append prepared ).
+    pub log_record: Option<Vec<u8>>,
     pub uid: String,
+    /// Update is rejected if the update queue is full ( HTTP requests, not internal work ).
+    pub queue_limited: bool,
+    /// Stream for output over the result limit ( read-only requests with --result-mode stream ).
+    pub stream: Option<mpsc::Sender<StreamPart>>,
+    /// Output has been streamed, only the remaining output is in the response.
+    pub streamed: bool,
 }
 
 impl Trans {
@@ -235,7 +1216,12 @@ impl Trans {
             readonly: false,
             run_time: Duration::from_micros(0),
             updates: 0,
+            save_time: Duration::from_micros(0),
+            log_record: None,
             uid: String::new(),
+            queue_limited: false,
+            stream: None,
+            streamed: false,
         }
     }
 
@@ -245,11 +1231,11 @@ impl Trans {
         result
     }
 
-    pub fn new_with_state(ss: Arc<SharedState>, ip: String) -> Self {
+    pub fn new_with_state(ss: Arc<SharedState>, ctx: RequestContext) -> Self {
         let mut result = Self::make();
         let mut ext = TransExt::new();
         ext.ss = Some(ss);
-        ext.uid = ip;
+        ext.ctx = ctx;
         result.x.ext = ext;
         result
     }
@@ -271,6 +1257,84 @@ impl Trans {
         self.x.set_extension(ext);
         result
     }
+
+    /// Run the SQL, if limit is not zero and the SELECT output exceeds limit bytes, the output is streamed if there is a stream,
+    /// otherwise the request is rejected ( status 507 ). Result is false if the request was rejected.
+    pub fn run(&mut self, db: &rustdb::DB, limit: usize) -> bool {
+        let sql = self.x.qy.sql.clone();
+        let stream = self.stream.take();
+        if limit == 0 {
+            db.run(&sql, &mut self.x);
+            return true;
+        }
+        let mut lt = LimitedTrans {
+            x: std::mem::take(&mut self.x),
+            limit,
+            over: false,
+            stream,
+            streaming: false,
+        };
+        db.run(&sql, &mut lt);
+        self.x = lt.x;
+        self.streamed = lt.streaming;
+        if lt.over && !lt.streaming {
+            self.reject(507, "Result too large"); // 507 = Insufficient Storage
+            return false;
+        }
+        true
+    }
+
+    /// Reset the response and extension, so the transaction can be run again with request context ctx.
+    pub fn reset(&mut self, ctx: RequestContext) {
+        self.x.rp = GenTransaction::new().rp;
+        let mut ext = TransExt::new();
+        if let Some(old) = self.x.get_extension().downcast_ref::<TransExt>() {
+            ext.ss = old.ss.clone();
+        }
+        ext.ctx = ctx;
+        self.x.ext = ext;
+    }
+
+    /// Replace the response with an error.
+    pub fn reject(&mut self, code: u16, msg: &str) {
+        let rp = &mut self.x.rp;
+        rp.status_code = code;
+        rp.headers.clear();
+        rp.output = msg.as_bytes().to_vec();
+    }
+
+    /// Take the response trailers set by SETTRAILER.
+    pub fn trailers(&mut self) -> Vec<(String, String)> {
+        let mut result = Vec::new();
+        let mut ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            result = std::mem::take(&mut ext.trailers);
+        }
+        self.x.set_extension(ext);
+        result
+    }
+
+    /// Call f with the request context.
+    pub fn with_ctx<R>(&mut self, f: impl FnOnce(&mut RequestContext) -> R) -> Option<R> {
+        let mut result = None;
+        let mut ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            result = Some(f(&mut ext.ctx));
+        }
+        self.x.set_extension(ext);
+        result
+    }
+
+    /// Get the requestor id and IP address.
+    pub fn ids(&mut self) -> (String, String) {
+        let mut result = (String::new(), String::new());
+        let ext = self.x.get_extension();
+        if let Some(ext) = ext.downcast_ref::<TransExt>() {
+            result = (ext.ctx.uid.clone(), ext.ctx.ip.clone());
+        }
+        self.x.set_extension(ext);
+        result
+    }
 }
 
 impl Default for Trans {
@@ -285,37 +1349,87 @@ pub struct UpdateMessage {
     pub reply: oneshot::Sender<Trans>,
 }
 
+/// Request metadata, constructed by request::process when the request headers have been read.
+/// It is carried in TransExt ( with the transaction ) to the update task and back, and is read by builtin functions.
+/// A new context ( with a new start time ) is constructed for each request. The wall clock time is not kept here, builtins use the query time ( qy.now ) which is logged with the transaction.
+/// Request headers are not copied into the context, the parts that are needed are parsed into the query ( e.g. cookies ) or fields here ( ip ).
+/// The number of bytes written is not included, as it is only known once the response has been sent.
+#[derive(Clone)]
+pub struct RequestContext {
+    /// Request id, unique while the server is running ( zero if not for a request ).
+    pub id: u64,
+    /// IP address of requestor ( from X-Real-IP if present ).
+    pub ip: String,
+    /// Id of requestor ( IP address or logged in user id, set by SETDOS ).
+    pub uid: String,
+    /// Time request processing started ( monotonic clock ).
+    pub start: std::time::Instant,
+    /// Session id ( from the session cookie, or set by SESSION ), empty if there is no session.
+    pub session: String,
+    /// Session id has been checked to exist ( an unknown id from the cookie is not used ).
+    pub session_checked: bool,
+    /// SQL is running on a read-only copy of the database.
+    pub readonly: bool,
+    /// Bytes read for the request ( headers, and the body once it has been read ).
+    pub bytes_read: u64,
+}
+
+impl RequestContext {
+    /// Construct context for request from ip, received at start.
+    pub fn new(ip: String, start: std::time::Instant) -> Self {
+        Self {
+            id: 0,
+            uid: ip.clone(),
+            ip,
+            start,
+            session: String::new(),
+            session_checked: false,
+            readonly: false,
+            bytes_read: 0,
+        }
+    }
+}
+
 /// Extra transaction data.
 pub struct TransExt {
     /// Shared State.
     pub ss: Option<Arc<SharedState>>,
-    /// Id of requestor ( IP address or logged in user id ).
-    pub uid: String,
+    /// Request metadata.
+    pub ctx: RequestContext,
     /// Signals there is new email to be sent.
     pub tx_email: bool,
+    /// Signals there is a new job in job.Queue.
+    pub tx_job: bool,
     /// Signals time to sleep.
     pub sleep: u64,
     /// Signals wait for new transaction to be logged
     pub trans_wait: bool,
     /// Signals wait for transactions to be flushed
     pub trans_flush: bool,
+    /// Signals immediate sync with master ( replica ).
+    pub rep_sync: bool,
     /// Transform html output to pdf.
     pub to_pdf: bool,
     /// Do not log transaction.
     pub no_log: bool,
+    /// Response trailers ( name, value ).
+    pub trailers: Vec<(String, String)>,
 }
 
 impl TransExt {
     fn new() -> Box<Self> {
         Box::new(Self {
             ss: None,
-            uid: String::new(),
+            ctx: RequestContext::new(String::new(), std::time::Instant::now()),
             tx_email: false,
+            tx_job: false,
             sleep: 0,
             trans_wait: false,
             trans_flush: false,
+            rep_sync: false,
             to_pdf: false,
             no_log: false,
+            trailers: Vec::new(),
         })
     }
 
@@ -360,3 +1474,91 @@ impl core::fmt::Display for Error {
         write!(f, "{}", self.code)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Move the last update time of the bucket back by secs ( as if secs have elapsed ).
+    fn elapse(b: &mut TokenBucket, secs: f64) {
+        b.last -= Duration::from_secs_f64(secs);
+    }
+
+    #[test]
+    fn take_waits() {
+        let mut b = TokenBucket::new(10.0, 2.0);
+        assert_eq!(b.take(), None);
+        assert_eq!(b.take(), None);
+        // Bucket is empty, next token is due in 0.1 seconds, the one after in 0.2 seconds.
+        let w = b.take().unwrap().as_secs_f64();
+        assert!(w > 0.09 && w <= 0.1, "{w}");
+        let w = b.take().unwrap().as_secs_f64();
+        assert!(w > 0.19 && w <= 0.2, "{w}");
+        // After the owed tokens are repaid the bucket refills, but no further than burst.
+        elapse(&mut b, 10.0);
+        assert_eq!(b.take(), None);
+        assert_eq!(b.take(), None);
+        assert!(b.take().is_some());
+    }
+
+    #[test]
+    fn take_no_limit() {
+        let mut b = TokenBucket::new(0.0, 0.0);
+        for _ in 0..100 {
+            assert_eq!(b.take(), None);
+        }
+    }
+
+    #[test]
+    fn try_take_refills() {
+        let mut b = TokenBucket::new(2.0, 3.0);
+        assert!(b.try_take() && b.try_take() && b.try_take());
+        assert!(!b.try_take());
+        // A failed try_take does not owe a token.
+        elapse(&mut b, 0.5);
+        assert!(b.try_take());
+        assert!(!b.try_take());
+        // Refill is capped at burst.
+        elapse(&mut b, 60.0);
+        assert!(b.try_take() && b.try_take() && b.try_take());
+        assert!(!b.try_take());
+        // Zero rate means no limit.
+        let mut b = TokenBucket::new(0.0, 0.0);
+        assert!(b.try_take() && b.try_take());
+    }
+
+    #[test]
+    fn result_limit() {
+        let ss = crate::test_state(&[]);
+        let apd = rustdb::AccessPagedData::new_writer(ss.spd.clone());
+        let db = rustdb::Database::new(apd, "", ss.bmap.clone());
+        let sql = "DECLARE i int WHILE i < 10000 BEGIN SELECT 'abcdefghij' SET i = i + 1 END";
+        let mut t = Trans::new();
+        t.x.qy.sql = Arc::new(sql.to_string());
+        assert!(t.run(&db, 0));
+        assert_eq!(t.x.rp.output.len(), 100_000);
+
+        // Rejected.
+        let mut t = Trans::new();
+        t.x.qy.sql = Arc::new(sql.to_string());
+        assert!(!t.run(&db, 1000));
+        assert_eq!(t.x.rp.status_code, 507);
+
+        // Streamed: status and headers, then the output in parts, the rest is left in the response.
+        let (tx, mut rx) = mpsc::channel(100);
+        let mut t = Trans::new();
+        t.x.qy.sql = Arc::new(sql.to_string());
+        t.x.rp.headers.push(("X-A".to_string(), "1".to_string()));
+        t.stream = Some(tx);
+        assert!(t.run(&db, 1000) && t.streamed);
+        assert!(matches!(rx.try_recv(), Ok(StreamPart::Head(200, h)) if h.len() == 1));
+        let mut total = 0;
+        while let Ok(StreamPart::Body(b)) = rx.try_recv() {
+            assert!(b.len() <= STREAM_PART + 10);
+            total += b.len();
+        }
+        assert_eq!(total + t.x.rp.output.len(), 100_000);
+        // The stream is closed once the SQL has run.
+        assert!(rx.is_closed());
+    }
+}
@@ -0,0 +1,331 @@
+use crate::share::Metrics;
+use rustdb::Storage;
+use std::sync::{atomic::Ordering, Arc};
+
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+
+/// File storage which retries operations that fail with a transient error ( with a delay which starts at 100ms and doubles ).
+/// A full disk is not treated as transient, retrying would only delay the failure.
+/// If a write or commit still fails, the storage is marked as failed and further writes and commits are ignored,
+/// so the update file is not reset, and the last committed updates are recovered when the server is restarted.
+pub struct RetryFileStorage {
+    file: Arc<std::fs::File>,
+    retries: u32,
+    metrics: Arc<Metrics>,
+}
+
+impl RetryFileStorage {
+    /// Construct from filename, retries is the maximum number of retries for an operation.
+    /// If metrics.read_only is set, the file is opened read-only and writes and commits are ignored.
+    pub fn new(filename: &str, retries: u32, metrics: Arc<Metrics>) -> Box<Self> {
+        let writable = !metrics.read_only.load(Ordering::Relaxed);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .create(writable)
+            .truncate(false)
+            .open(filename)
+            .unwrap();
+        Box::new(Self {
+            file: Arc::new(file),
+            retries,
+            metrics,
+        })
+    }
+
+    /// Perform operation, retrying transient errors.
+    fn retry<T>(
+        &self,
+        what: &str,
+        mut op: impl FnMut(&std::fs::File) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let (mut n, mut delay) = (0, 100);
+        loop {
+            match op(&self.file) {
+                Err(e) if n < self.retries && is_transient(&e) => {
+                    n += 1;
+                    println!("Storage {what} error: {e} - retry {n} in {delay}ms");
+                    self.metrics.storage_retries.fetch_add(1, Ordering::Relaxed);
+                    std::thread::sleep(std::time::Duration::from_millis(delay));
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Has a write or commit failed ( or is the storage read-only )?
+    fn failed(&self) -> bool {
+        self.metrics.storage_failed.load(Ordering::Relaxed)
+            || self.metrics.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Mark storage as failed.
+    fn fail(&self, what: &str, e: std::io::Error) {
+        println!("Storage {what} failed: {e} - updates are no longer saved, restart required");
+        self.metrics.storage_failed.store(true, Ordering::Relaxed);
+    }
+}
+
+// Storage methods cannot return errors, if size or read fail the storage is marked as failed ( so no further changes are written ).
+impl Storage for RetryFileStorage {
+    fn size(&self) -> u64 {
+        match self.retry("size", |f| Ok(f.metadata()?.len())) {
+            Ok(size) => size,
+            Err(e) => {
+                self.fail("size", e);
+                0
+            }
+        }
+    }
+
+    fn read(&self, off: u64, bytes: &mut [u8]) {
+        let result = self.retry("read", |f| {
+            let mut done = 0;
+            while done < bytes.len() {
+                let n = read_at(f, &mut bytes[done..], off + done as u64)?;
+                if n == 0 {
+                    break; // End of file.
+                }
+                done += n;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            bytes.fill(0);
+            self.fail("read", e);
+        }
+    }
+
+    fn write(&mut self, off: u64, bytes: &[u8]) {
+        if self.failed() {
+            return;
+        }
+        let result = self.retry("write", |f| {
+            let mut done = 0;
+            while done < bytes.len() {
+                let n = write_at(f, &bytes[done..], off + done as u64)?;
+                if n == 0 {
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+                done += n;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            self.fail("write", e);
+        }
+    }
+
+    fn commit(&mut self, size: u64) {
+        if self.failed() {
+            return;
+        }
+        let result = self.retry("commit", |f| {
+            f.set_len(size)?;
+            f.sync_all()
+        });
+        if let Err(e) = result {
+            self.fail("commit", e);
+        }
+    }
+
+    fn clone(&self) -> Box<dyn Storage> {
+        Box::new(Self {
+            file: self.file.clone(),
+            retries: self.retries,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+/// Storage which waits for committed updates to be applied ( a checkpoint ) once the data written since the last checkpoint reaches a limit.
+/// Commits are applied asynchronously, so this bounds the committed updates not yet applied to the database file, which are recovered from the update file after a crash.
+pub struct CheckpointStorage {
+    stg: Box<dyn Storage>,
+    limit: u64,
+    written: u64,
+    metrics: Arc<Metrics>,
+}
+
+impl CheckpointStorage {
+    /// Construct from underlying storage, limit is in bytes ( 0 means no checkpoints ).
+    pub fn new(stg: Box<dyn Storage>, limit: u64, metrics: Arc<Metrics>) -> Box<Self> {
+        Box::new(Self {
+            stg,
+            limit,
+            written: 0,
+            metrics,
+        })
+    }
+}
+
+impl Storage for CheckpointStorage {
+    fn size(&self) -> u64 {
+        self.stg.size()
+    }
+
+    fn read(&self, off: u64, bytes: &mut [u8]) {
+        self.stg.read(off, bytes);
+    }
+
+    fn write(&mut self, off: u64, bytes: &[u8]) {
+        self.written += bytes.len() as u64;
+        self.stg.write(off, bytes);
+    }
+
+    fn write_data(&mut self, off: u64, data: rustdb::Data, doff: usize, len: usize) {
+        self.written += len as u64;
+        self.stg.write_data(off, data, doff, len);
+    }
+
+    fn commit(&mut self, size: u64) {
+        self.stg.commit(size);
+        if self.limit > 0 && self.written >= self.limit {
+            self.stg.wait_complete();
+            self.written = 0;
+            self.metrics.upd_checkpoints.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn wait_complete(&self) {
+        self.stg.wait_complete();
+    }
+}
+
+/// Reset the update file if it holds no complete commit ( the end position in its header is zero ), called after a clean shutdown.
+/// Result is the remaining size of the update file, non-zero if it holds a commit which is applied at the next startup.
+pub fn cleanup_upd(filename: &str) -> std::io::Result<u64> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(filename)?;
+    let len = file.metadata()?.len();
+    let mut end = [0; 8];
+    if len >= 8 {
+        read_at(&file, &mut end, 0)?;
+    }
+    if len > 0 && u64::from_le_bytes(end) == 0 {
+        file.set_len(0)?;
+        file.sync_all()?;
+        return Ok(0);
+    }
+    Ok(len)
+}
+
+/// Check whether error may be transient.
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(e.kind(), Interrupted | WouldBlock | TimedOut | ResourceBusy)
+}
+
+#[cfg(unix)]
+fn read_at(f: &std::fs::File, buf: &mut [u8], off: u64) -> std::io::Result<usize> {
+    f.read_at(buf, off)
+}
+
+#[cfg(unix)]
+fn write_at(f: &std::fs::File, buf: &[u8], off: u64) -> std::io::Result<usize> {
+    f.write_at(buf, off)
+}
+
+#[cfg(windows)]
+fn read_at(f: &std::fs::File, buf: &mut [u8], off: u64) -> std::io::Result<usize> {
+    f.seek_read(buf, off)
+}
+
+#[cfg(windows)]
+fn write_at(f: &std::fs::File, buf: &[u8], off: u64) -> std::io::Result<usize> {
+    f.seek_write(buf, off)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustdb::AtomicFile;
+
+    /// Create empty test directory, returning the paths of the database and update files in it.
+    fn test_files(name: &str) -> (String, String) {
+        let dir = std::env::temp_dir().join(format!("rustweb2-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |f: &str| dir.join(f).to_str().unwrap().to_string();
+        (path("rustweb.rustdb"), path("rustweb.upd"))
+    }
+
+    /// Update file holding one write of data at start, complete is false if the commit was interrupted before the end position was written.
+    fn upd_file(size: u64, start: u64, data: &[u8], complete: bool) -> Vec<u8> {
+        let end = 32 + data.len() as u64;
+        let mut upd = Vec::new();
+        upd.extend((if complete { end } else { 0 }).to_le_bytes());
+        upd.extend(size.to_le_bytes());
+        upd.extend(start.to_le_bytes());
+        upd.extend((data.len() as u64).to_le_bytes());
+        upd.extend(data);
+        upd
+    }
+
+    /// Simulate a crash with a non-empty update file, then open the storage as at startup.
+    fn crash(name: &str, complete: bool) -> (Vec<u8>, u64) {
+        let (db, upd) = test_files(name);
+        std::fs::write(&db, [0; 32]).unwrap();
+        std::fs::write(&upd, upd_file(32, 8, b"data", complete)).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let stg = AtomicFile::new(
+            RetryFileStorage::new(&db, 0, metrics.clone()),
+            RetryFileStorage::new(&upd, 0, metrics),
+        );
+        stg.wait_complete();
+        let upd_size = cleanup_upd(&upd).unwrap();
+        (std::fs::read(&db).unwrap(), upd_size)
+    }
+
+    #[test]
+    fn upd_recovered() {
+        // A complete commit in the update file is applied to the database file.
+        let (db, upd_size) = crash("recovered", true);
+        assert_eq!(&db[8..12], b"data");
+        assert_eq!(db.len(), 32);
+        assert_eq!(upd_size, 0);
+    }
+
+    #[test]
+    fn upd_interrupted() {
+        // An incomplete commit is discarded, and the update file is reset.
+        let (db, upd_size) = crash("interrupted", false);
+        assert_eq!(db, [0; 32]);
+        assert_eq!(upd_size, 0);
+    }
+
+    #[test]
+    fn upd_pending() {
+        // A complete commit left in the update file is not removed by cleanup.
+        let (_, upd) = test_files("pending");
+        let data = upd_file(32, 8, b"data", true);
+        std::fs::write(&upd, &data).unwrap();
+        assert_eq!(cleanup_upd(&upd).unwrap(), data.len() as u64);
+    }
+
+    #[test]
+    fn checkpoint() {
+        let metrics = Arc::new(Metrics::default());
+        let mut stg = CheckpointStorage::new(rustdb::MemFile::new(), 16, metrics.clone());
+        let checkpoints = || metrics.upd_checkpoints.load(Ordering::Relaxed);
+        stg.write(0, &[1; 10]);
+        stg.commit(10);
+        assert_eq!(checkpoints(), 0);
+        stg.write(10, &[2; 10]);
+        stg.commit(20);
+        assert_eq!(checkpoints(), 1);
+        stg.write(0, &[3; 10]);
+        stg.commit(20);
+        assert_eq!(checkpoints(), 1);
+        let mut data = [0; 20];
+        stg.read(0, &mut data);
+        assert_eq!(data[..10], [3; 10]);
+        assert_eq!(data[10..], [2; 10]);
+    }
+}
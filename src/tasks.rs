@@ -1,18 +1,47 @@
-use crate::share::{SharedState, Trans};
-use rustdb::{AccessPagedData, Database, Part};
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use crate::share::{Diverge, Metrics, SharedState, Trans};
+use rustdb::{AccessPagedData, Database, ObjRef, Part};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 
-/// Task that calls u_decay every 10 seconds
+/// Task that calls u_decay ( and updates metric rates ) every 10 seconds
 pub async fn u_decay_loop(ss: Arc<SharedState>) {
     loop {
         tokio::time::sleep(core::time::Duration::from_secs(10)).await;
         ss.u_decay();
+        ss.metrics.tick(10);
+    }
+}
+
+/// Task that pushes metrics to a Prometheus Pushgateway every interval seconds.
+/// After a failure, the delay doubles up to 10 times the interval.
+pub async fn push_loop(url: String, interval: u64, ss: Arc<SharedState>) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(interval.max(10)))
+        .build()
+        .unwrap();
+    let mut delay = interval;
+    loop {
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+        let body = ss.metrics.text();
+        match client.put(&url).body(body).send().await {
+            Ok(r) if r.status().is_success() => {
+                delay = interval;
+                continue;
+            }
+            Ok(r) => println!("Metrics push to {url} failed status={}", r.status()),
+            Err(e) => println!("Metrics push to {url} failed err={e}"),
+        }
+        delay = (delay * 2).min(interval * 10);
     }
 }
 
 /// Task for backing up master database
-pub async fn backup_loop(is_new: bool, state: Arc<SharedState>) {
+pub async fn backup_loop(
+    is_new: bool,
+    mut sync_rx: mpsc::UnboundedReceiver<oneshot::Sender<u64>>,
+    state: Arc<SharedState>,
+) {
     if is_new {
         let sql = rget(state.clone(), "/log-getall").await;
         let sql = std::str::from_utf8(&sql).unwrap().to_string();
@@ -26,28 +55,153 @@ pub async fn backup_loop(is_new: bool, state: Arc<SharedState>) {
         st.x.qy.sql = Arc::new("EXEC log.InitReplication()".to_string());
         let _st = state.process(st).await;
     }
-    let mut fetch = {
-        let mut st = Trans::new();
-        st.log = false;
-        st.x.qy.sql = Arc::new("EXEC log.GetFetch()".to_string());
-        st = state.process(st).await;
-        let s = std::str::from_utf8(&st.x.rp.output).unwrap();
-        s.parse::<u64>().unwrap()
-    };
+    let mut fetch = get_fetch(&state).await;
     println!("Backup from fetch={}", fetch);
+    // Compare last local transaction with the master, if the master does not have it, check again when the next transaction is fetched.
+    let mut check = false;
+    if fetch > 1 {
+        match same_as_master(&state, fetch - 1).await {
+            Some(false) if !diverged(&state) => return,
+            None => check = true,
+            _ => {}
+        }
+    }
     loop {
         let url = format!("/log-get?k={fetch}");
-        let ser = rget(state.clone(), &url).await;
+        let ser = tokio::select! {
+            ser = rget(state.clone(), &url) => ser,
+            Some(reply) = sync_rx.recv() => {
+                // Immediate sync requested, fetch the transactions the master has without waiting.
+                let mut n = 0;
+                loop {
+                    let url = format!("/log-get?k={fetch}&check=1");
+                    let ser = rget(state.clone(), &url).await;
+                    if ser.is_empty() {
+                        break;
+                    }
+                    match save(&state, fetch, &mut check, ser).await {
+                        Saved::Ok => {
+                            fetch += 1;
+                            n += 1;
+                        }
+                        Saved::Retry => break,
+                        Saved::Stop => {
+                            let _ = reply.send(n);
+                            return;
+                        }
+                    }
+                }
+                let _ = reply.send(n);
+                continue;
+            }
+        };
         if !ser.is_empty() {
-            let mut st = Trans::new();
-            let mut part = Part::default();
-            part.data = Arc::new(ser);
-            st.x.qy.parts.push(part);
-            st.x.qy.sql = Arc::new("EXEC log.Save()".to_string());
-            state.process(st).await;
-            println!("Saved Transaction Id={fetch}");
-            fetch += 1;
-            state.new_trans();
+            match save(&state, fetch, &mut check, ser).await {
+                Saved::Ok => fetch += 1,
+                Saved::Retry => tokio::time::sleep(Duration::from_secs(60)).await,
+                Saved::Stop => return,
+            }
+        }
+    }
+}
+
+/// Result of saving a replicated transaction.
+enum Saved {
+    Ok,
+    /// Database size or memory limit reached, retry later.
+    Retry,
+    /// Replica has diverged from master, stop replication.
+    Stop,
+}
+
+/// Save transaction fetch ( ser ) fetched from master.
+async fn save(state: &Arc<SharedState>, fetch: u64, check: &mut bool, ser: Vec<u8>) -> Saved {
+    if *check {
+        // Master has transaction fetch, so if fetch-1 is missing it was deleted.
+        *check = false;
+        if same_as_master(state, fetch - 1).await == Some(false) && !diverged(state) {
+            return Saved::Stop;
+        }
+    }
+    if get_fetch(state).await != fetch && !diverged(state) {
+        return Saved::Stop;
+    }
+    let mut st = Trans::new();
+    let mut part = Part::default();
+    part.data = Arc::new(ser);
+    st.x.qy.parts.push(part);
+    st.x.qy.sql = Arc::new("EXEC log.Save()".to_string());
+    let st = state.process(st).await;
+    if st.x.rp.status_code != 200 {
+        println!(
+            "Transaction Id={fetch} not saved status={}",
+            st.x.rp.status_code
+        );
+        return Saved::Retry;
+    }
+    println!("Saved Transaction Id={fetch}");
+    state.new_trans();
+    Saved::Ok
+}
+
+/// Run startup SQL in order, each as a separate transaction, printing the result.
+pub async fn startup(list: Vec<String>, state: Arc<SharedState>) {
+    for sql in list {
+        let mut st = Trans::new();
+        st.x.qy.sql = Arc::new(sql.clone());
+        let st = state.process(st).await;
+        let rp = &st.x.rp;
+        if !rp.err.is_empty() {
+            println!("Startup SQL {sql} error: {}", rp.err);
+        } else if rp.status_code != 200 {
+            println!("Startup SQL {sql} failed status={}", rp.status_code);
+        } else {
+            let output = String::from_utf8_lossy(&rp.output);
+            println!("Startup SQL {sql} done: {output}");
+        }
+    }
+}
+
+/// Get Id of next transaction to be saved in local log.
+async fn get_fetch(state: &Arc<SharedState>) -> u64 {
+    let mut st = Trans::new();
+    st.log = false;
+    st.x.qy.sql = Arc::new("EXEC log.GetFetch()".to_string());
+    st = state.process(st).await;
+    let s = std::str::from_utf8(&st.x.rp.output).unwrap();
+    s.parse::<u64>().unwrap()
+}
+
+/// Compare local transaction k with master transaction k, None if the master does not have transaction k.
+async fn same_as_master(state: &Arc<SharedState>, k: u64) -> Option<bool> {
+    let mut st = Trans::new();
+    st.log = false;
+    st.x.qy.sql = Arc::new(format!(
+        "DECLARE d binary SET d = data FROM log.Transaction WHERE Id = {k} SELECT d"
+    ));
+    st = state.process(st).await;
+    let master = rget(state.clone(), &format!("/log-get?k={k}&check=1")).await;
+    if master.is_empty() {
+        None
+    } else {
+        Some(master == st.x.rp.output)
+    }
+}
+
+/// Replica log has diverged from master, result is true if replication should continue.
+fn diverged(state: &SharedState) -> bool {
+    state
+        .metrics
+        .replica_diverged
+        .store(true, Ordering::Relaxed);
+    match state.on_diverge {
+        Diverge::Stop => {
+            println!("Replica has diverged from master, replication stopped. Re-initialise the replica from the master to recover ( see README ).");
+            false
+        }
+        Diverge::Continue => {
+            println!("Replica has diverged from master, replication continuing.");
+            true
         }
     }
 }
@@ -70,19 +224,24 @@ async fn sleep_real(secs: u64) {
     }
 }
 
-/// Get data from master server, retries in case of error
+/// Get data from master server, retries in case of error ( with increasing delay )
 async fn rget(state: Arc<SharedState>, query: &str) -> Vec<u8> {
     // get a client builder
-    let client = reqwest::Client::builder()
+    let mut builder = reqwest::Client::builder()
         .default_headers(reqwest::header::HeaderMap::new())
-        .build()
-        .unwrap();
+        .connect_timeout(Duration::from_secs(state.rep_connect_timeout));
+    if state.rep_read_timeout > 0 {
+        builder = builder.read_timeout(Duration::from_secs(state.rep_read_timeout));
+    }
+    let client = builder.build().unwrap();
+    let mut delay = 10;
     loop {
         let mut retry_delay = true;
         let req = client
             .get(state.replicate_source.clone() + query)
             .header("Cookie", state.replicate_credentials.clone());
 
+        let m = &state.metrics;
         tokio::select! {
             response = req.send() =>
             {
@@ -93,31 +252,51 @@ async fn rget(state: Arc<SharedState>, query: &str) -> Vec<u8> {
                      if status.is_success()
                      {
                          match r.bytes().await {
-                            Ok(b) => { return b.to_vec(); }
-                            Err(e) => { println!("rget failed to get bytes err={e}" ); }
+                            Ok(b) => {
+                                m.sync_ok.fetch_add(1, Ordering::Relaxed);
+                                return b.to_vec();
+                            }
+                            Err(e) => {
+                                sync_failed(m, e.is_timeout());
+                                println!("rget failed to get bytes err={e}" );
+                            }
                          }
                      } else {
+                         m.sync_error.fetch_add(1, Ordering::Relaxed);
                          println!("rget bad response status = {status}");
                      }
                   }
                   Err(e) => {
+                    sync_failed(m, e.is_timeout());
                     println!("rget send error {e}");
                   }
                }
             }
-            _ = sleep_real(800) =>
+            _ = sleep_real(state.rep_timeout) =>
             {
-              println!( "rget timed out after 800 seconds" );
+              m.sync_timeout.fetch_add(1, Ordering::Relaxed);
+              println!( "rget timed out after {} seconds", state.rep_timeout );
               retry_delay = false;
             }
         }
         if retry_delay {
-            // Wait before retrying after error/timeout.
-            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+            // Wait before retrying after error, doubling delay up to 5 minutes.
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            delay = (delay * 2).min(300);
         }
     }
 }
 
+/// Count failed replication request.
+fn sync_failed(m: &Metrics, timeout: bool) {
+    let counter = if timeout {
+        &m.sync_timeout
+    } else {
+        &m.sync_error
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
 /// Task for sleeping - calls timed.Run once sleep time has elapsed
 pub async fn sleep_loop(mut rx: mpsc::UnboundedReceiver<u64>, state: Arc<SharedState>) {
     let mut sleep_micro = 5000000;
@@ -128,6 +307,7 @@ pub async fn sleep_loop(mut rx: mpsc::UnboundedReceiver<u64>, state: Arc<SharedS
             {
               if state.is_master
               {
+                state.bulk_wait().await;
                 let mut st = Trans::new();
                 st.x.qy.sql = Arc::new("EXEC timed.Run()".to_string());
                 state.process(st).await;
@@ -137,22 +317,123 @@ pub async fn sleep_loop(mut rx: mpsc::UnboundedReceiver<u64>, state: Arc<SharedS
     }
 }
 
-/// Task that sends emails
+/// Task that runs jobs from job.Queue ( added by ENQUEUE ), checking for jobs due to be retried every 10 seconds.
+pub async fn job_loop(mut rx: mpsc::UnboundedReceiver<()>, state: Arc<SharedState>) {
+    loop {
+        tokio::select! {
+            _ = rx.recv() => {}
+            _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+        }
+        // Jobs are deferred until bulk mode ends.
+        state.bulk_wait().await;
+        for (id, payload) in due_jobs(&state) {
+            let mut st = Trans::new();
+            // The handler SQL is read in the same transaction that runs it, so it cannot be changed in between.
+            // It is run by EXECUTE, so the job is deleted even if the handler executes RETURN.
+            st.x.qy.sql = Arc::new(format!(
+                "DECLARE k string, s string
+SET k = Kind FROM job.Queue WHERE Id = {id}
+SET s = Sql FROM job.Handler WHERE Kind = k
+IF s = '' THROW 'No handler for job kind ' | k
+EXECUTE( s )
+DELETE FROM job.Queue WHERE Id = {id}"
+            ));
+            st.x.qy.params.insert("job".to_string(), id.to_string());
+            st.x.qy.params.insert("payload".to_string(), payload);
+            let st = state.process(st).await;
+            let err = if !st.x.rp.err.is_empty() {
+                st.x.rp.err
+            } else if st.x.rp.status_code != 200 {
+                format!("status {}", st.x.rp.status_code)
+            } else {
+                continue;
+            };
+            job_failed(&state, id, &err).await;
+        }
+    }
+}
+
+/// Update the database to reflect a job failed ( it is retried or moved to job.Failed ).
+async fn job_failed(state: &SharedState, id: u64, err: &str) {
+    let mut st = Trans::new();
+    let err = err.replace('\'', "''");
+    st.x.qy.sql = Arc::new(format!("EXEC job.Failed({id},'{err}')"));
+    state.process(st).await;
+}
+
+/// Value of date.Ticks() ( micro-seconds since 1 Jan 0000 ) at January 1, 1970 0:00:00 UTC.
+/// Must match the offset in the definition of date.Ticks in init::INITSQL.
+const TICKS_OFFSET: i64 = 62135596800000000 + 366 * 24 * 3600 * 1000000;
+
+/// Get current time as date.Ticks() value.
+fn ticks() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as i64
+        + TICKS_OFFSET
+}
+
+/// Get jobs which are due to run ( id, payload ).
+fn due_jobs(state: &SharedState) -> Vec<(u64, String)> {
+    let mut result = Vec::new();
+    let apd = AccessPagedData::new_reader(state.spd.clone());
+    let db = Database::new(apd, "", state.bmap.clone());
+    let Some(qt) = db.get_table(&ObjRef::new("job", "Queue")) else {
+        return result;
+    };
+    let now = ticks();
+    for (pp, off) in qt.scan(&db) {
+        let p = pp.borrow();
+        let a = qt.access(&p, off);
+        if a.int(3) <= now {
+            result.push((a.id(), a.str(&db, 1)));
+        }
+    }
+    result
+}
+
+/// Task that sends emails, emails in email.Queue are sent once their send time ( at ) is reached.
 pub async fn email_loop(mut rx: mpsc::UnboundedReceiver<()>, state: Arc<SharedState>) {
+    // Time until the next scheduled email is due ( the queue is checked at startup ).
+    let mut wait: Option<Duration> = Some(Duration::ZERO);
     loop {
         let mut send_list = Vec::new();
         {
-            let _ = rx.recv().await;
+            match wait {
+                Some(d) => tokio::select! {
+                    _ = rx.recv() => {}
+                    _ = tokio::time::sleep(d) => {}
+                },
+                None => {
+                    let _ = rx.recv().await;
+                }
+            }
+            wait = None;
+            let now = ticks();
             let apd = AccessPagedData::new_reader(state.spd.clone());
             let db = Database::new(apd, "", state.bmap.clone());
-            let qt = db.table("email", "Queue");
-            let mt = db.table("email", "Msg");
-            let at = db.table("email", "SmtpAccount");
+            // The tables may not exist yet if the database is new.
+            let (Some(qt), Some(mt), Some(at)) = (
+                db.get_table(&ObjRef::new("email", "Queue")),
+                db.get_table(&ObjRef::new("email", "Msg")),
+                db.get_table(&ObjRef::new("email", "SmtpAccount")),
+            ) else {
+                continue;
+            };
 
+            // Send time column ( missing for databases created before SENDMAILAT, until migrated ).
+            let at_col = qt.info.get("at").copied();
             for (pp, off) in qt.scan(&db) {
                 let p = &pp.borrow();
                 let a = qt.access(p, off);
                 let msg = a.int(0) as u64;
+                let due = at_col.map_or(0, |col| a.int(col));
+                if due > now {
+                    let d = Duration::from_micros((due - now) as u64);
+                    wait = Some(wait.map_or(d, |w| w.min(d)));
+                    continue;
+                }
 
                 if let Some((pp, off)) = mt.id_get(&db, msg) {
                     let p = &pp.borrow();
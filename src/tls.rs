@@ -0,0 +1,214 @@
+use rustc_hash::FxHashMap as HashMap;
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    crypto::CryptoProvider,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    version::{TLS12, TLS13},
+    ServerConfig, SupportedProtocolVersion,
+};
+
+/// Minimum TLS protocol version.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TlsVersion {
+    /// TLS 1.2 and TLS 1.3.
+    #[value(name = "1.2")]
+    Tls12,
+    /// TLS 1.3 only.
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+/// Certificates selected by the client SNI host name, with a default for other host names.
+#[derive(Debug)]
+struct SniResolver {
+    /// Certificates by host name ( lower case, may start with "*." to match any one label ).
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name();
+        Some(select(&self.hosts, &self.default, name).clone())
+    }
+}
+
+/// Select value for SNI host name, an exact match, else a "*." wildcard match for the host name without its first label, else the default.
+fn select<'a, T>(hosts: &'a HashMap<String, T>, default: &'a T, name: Option<&str>) -> &'a T {
+    let Some(name) = name.map(|n| n.to_ascii_lowercase()) else {
+        return default;
+    };
+    if let Some(x) = hosts.get(&name) {
+        return x;
+    }
+    match name.split_once('.') {
+        Some((_, rest)) => hosts.get(&format!("*.{rest}")).unwrap_or(default),
+        None => default,
+    }
+}
+
+/// Parse --tls-sni spec host=cert,key.
+pub fn parse_sni(spec: &str) -> Result<(String, String, String), String> {
+    let (host, files) = spec.split_once('=').ok_or("expected host=cert,key")?;
+    let (cert, key) = files.split_once(',').ok_or("expected host=cert,key")?;
+    if host.is_empty() || cert.is_empty() || key.is_empty() {
+        return Err("expected host=cert,key".to_string());
+    }
+    Ok((host.to_ascii_lowercase(), cert.to_string(), key.to_string()))
+}
+
+/// Load certificate chain and private key from PEM files.
+fn load(cert: &str, key: &str, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>, String> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("certificate file {cert}: {e}"))?;
+    if chain.is_empty() {
+        return Err(format!("certificate file {cert}: no certificates"));
+    }
+    let pk = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("key file {key}: {e}"))?;
+    let ck =
+        CertifiedKey::from_der(chain, pk, provider).map_err(|e| format!("key file {key}: {e}"))?;
+    Ok(Arc::new(ck))
+}
+
+/// Get crypto provider with the cipher suites allowed by ciphers ( comma separated names, empty means all ) and min,
+/// and the protocol versions. There must be at least one usable cipher suite.
+fn provider(
+    min: TlsVersion,
+    ciphers: &str,
+) -> Result<(CryptoProvider, Vec<&'static SupportedProtocolVersion>), String> {
+    let mut provider = tokio_rustls::rustls::crypto::ring::default_provider();
+    let names: Vec<&str> = ciphers
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .collect();
+    let suite_name = |cs: &tokio_rustls::rustls::SupportedCipherSuite| format!("{:?}", cs.suite());
+    for name in &names {
+        if !provider
+            .cipher_suites
+            .iter()
+            .any(|cs| suite_name(cs) == *name)
+        {
+            return Err(format!("unknown cipher suite {name}"));
+        }
+    }
+    let versions = match min {
+        TlsVersion::Tls12 => vec![&TLS13, &TLS12],
+        TlsVersion::Tls13 => vec![&TLS13],
+    };
+    provider.cipher_suites.retain(|cs| {
+        versions.contains(&cs.version()) && (names.is_empty() || names.contains(&&*suite_name(cs)))
+    });
+    if provider.cipher_suites.is_empty() {
+        return Err("no usable cipher suites".to_string());
+    }
+    Ok((provider, versions))
+}
+
+/// Get TLS server configuration, cert and key are the default certificate, sni gives certificates for specific host names.
+/// min is the minimum protocol version, and ciphers lists the allowed cipher suites ( empty means all ).
+/// The effective configuration is printed.
+pub fn config(
+    cert: &str,
+    key: &str,
+    sni: &[(String, String, String)],
+    min: TlsVersion,
+    ciphers: &str,
+) -> Result<Arc<ServerConfig>, String> {
+    let (provider, versions) = provider(min, ciphers)?;
+    let mut hosts = HashMap::default();
+    for (host, cert, key) in sni {
+        hosts.insert(host.clone(), load(cert, key, &provider)?);
+    }
+    let resolver = SniResolver {
+        hosts,
+        default: load(cert, key, &provider)?,
+    };
+    let names: Vec<String> = versions
+        .iter()
+        .map(|v| format!("{:?}", v.version))
+        .collect();
+    let suites: Vec<String> = provider
+        .cipher_suites
+        .iter()
+        .map(|cs| format!("{:?}", cs.suite()))
+        .collect();
+    println!(
+        "TLS protocol versions: {} cipher suites: {}",
+        names.join(", "),
+        suites.join(", ")
+    );
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(&versions)
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sni_spec() {
+        let spec = parse_sni("Example.com=a.pem,b.pem").unwrap();
+        assert_eq!(spec, ("example.com".into(), "a.pem".into(), "b.pem".into()));
+        assert!(parse_sni("example.com=a.pem").is_err());
+        assert!(parse_sni("a.pem,b.pem").is_err());
+        assert!(parse_sni("=a.pem,b.pem").is_err());
+    }
+
+    #[test]
+    fn cipher_suites() {
+        let suites = |min, ciphers| {
+            provider(min, ciphers).map(|(p, v)| {
+                let names: Vec<String> = p
+                    .cipher_suites
+                    .iter()
+                    .map(|cs| format!("{:?}", cs.suite()))
+                    .collect();
+                (names, v.len())
+            })
+        };
+        let (all, n) = suites(TlsVersion::Tls12, "").unwrap();
+        assert_eq!(n, 2);
+        assert!(all.iter().any(|x| x.starts_with("TLS13_")));
+        assert!(all.iter().any(|x| x.starts_with("TLS_ECDHE_")));
+        let (tls13, n) = suites(TlsVersion::Tls13, "").unwrap();
+        assert_eq!(n, 1);
+        assert!(tls13.iter().all(|x| x.starts_with("TLS13_")));
+        let ciphers = "TLS13_AES_128_GCM_SHA256, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256";
+        let (some, _) = suites(TlsVersion::Tls12, ciphers).unwrap();
+        assert_eq!(some.len(), 2);
+        assert_eq!(
+            suites(TlsVersion::Tls12, "TLS_BOGUS").err().unwrap(),
+            "unknown cipher suite TLS_BOGUS"
+        );
+        // Only TLS 1.2 suites with TLS 1.3 only leaves no usable suites.
+        let ciphers = "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256";
+        assert_eq!(
+            suites(TlsVersion::Tls13, ciphers).err().unwrap(),
+            "no usable cipher suites"
+        );
+    }
+
+    #[test]
+    fn sni_select() {
+        let mut hosts = HashMap::default();
+        hosts.insert("example.com".to_string(), 1);
+        hosts.insert("*.example.com".to_string(), 2);
+        hosts.insert("a.example.com".to_string(), 3);
+        let get = |name| *select(&hosts, &0, name);
+        assert_eq!(get(Some("Example.COM")), 1);
+        assert_eq!(get(Some("b.example.com")), 2);
+        assert_eq!(get(Some("a.example.com")), 3);
+        assert_eq!(get(Some("x.b.example.com")), 0);
+        assert_eq!(get(Some("example.org")), 0);
+        assert_eq!(get(None), 0);
+    }
+}
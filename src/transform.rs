@@ -0,0 +1,231 @@
+use rustdb::gentrans::GenResponse;
+
+/// Response body transform.
+pub type Transform = fn(&[u8]) -> Vec<u8>;
+
+/// Get transform by name.
+pub fn get(name: &str) -> Option<Transform> {
+    match name {
+        "html" => Some(html),
+        "css" => Some(css),
+        _ => None,
+    }
+}
+
+/// Parse transform specification of form content-type=name.
+pub fn parse(spec: &str) -> Result<(String, Transform), String> {
+    let (ct, name) = spec
+        .split_once('=')
+        .ok_or(format!("transform {spec} should be content-type=name"))?;
+    let f = get(name).ok_or(format!("unknown transform {name}"))?;
+    Ok((ct.to_ascii_lowercase(), f))
+}
+
+/// Apply transforms which match the response content type ( in order ).
+pub fn apply(list: &[(String, Transform)], rp: &mut GenResponse) {
+    if list.is_empty() {
+        return;
+    }
+    let ct = rp
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.split(';').next().unwrap().trim().to_ascii_lowercase());
+    if let Some(ct) = ct {
+        for (t, f) in list {
+            if *t == ct {
+                rp.output = f(&rp.output);
+            }
+        }
+    }
+}
+
+/// Elements whose content is copied unchanged by html.
+const RAW: [&[u8]; 4] = [b"pre", b"textarea", b"script", b"style"];
+
+/// Minify html: comments are removed and runs of white space between tags are replaced by a single space or newline.
+/// Tags, and the content of pre, textarea, script and style elements are unchanged.
+fn html(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let (mut i, n) = (0, s.len());
+    while i < n {
+        let b = s[i];
+        if s[i..].starts_with(b"<!--") && !s[i..].starts_with(b"<!--[") {
+            // Comment ( conditional comments are kept ).
+            i = find(s, i + 4, b"-->").map_or(n, |k| k + 3);
+        } else if b == b'<' && i + 1 < n && (s[i + 1].is_ascii_alphabetic() || s[i + 1] == b'/') {
+            let end = if let Some(tag) = RAW.iter().find(|t| is_tag(&s[i + 1..], t)) {
+                let close = [&b"</"[..], tag].concat();
+                find(s, i + 1, &close).unwrap_or(n)
+            } else {
+                tag_end(s, i)
+            };
+            out.extend_from_slice(&s[i..end]);
+            i = end;
+        } else if b.is_ascii_whitespace() {
+            let start = i;
+            while i < n && s[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let nl = s[start..i].contains(&b'\n');
+            out.push(if nl { b'\n' } else { b' ' });
+        } else {
+            out.push(b);
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Minify css: comments are removed, runs of white space are replaced by a single space,
+/// white space next to { } ; and , is removed. Strings are unchanged.
+fn css(s: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(s.len());
+    let (mut i, n) = (0, s.len());
+    let punct = |b: u8| matches!(b, b'{' | b'}' | b';' | b',');
+    while i < n {
+        let b = s[i];
+        if b == b'/' && i + 1 < n && s[i + 1] == b'*' {
+            i = find(s, i + 2, b"*/").map_or(n, |k| k + 2);
+        } else if b == b'"' || b == b'\'' {
+            let end = quote_end(s, i, true);
+            out.extend_from_slice(&s[i..end]);
+            i = end;
+        } else if b.is_ascii_whitespace() {
+            while i < n && s[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let after = i < n && punct(s[i]);
+            let before = out.last().is_none_or(|b| punct(*b));
+            if !before && !after {
+                out.push(b' ');
+            }
+        } else {
+            if punct(b) && out.last() == Some(&b' ') {
+                out.pop();
+            }
+            out.push(b);
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Find pat in s starting at start ( ASCII case insensitive ).
+fn find(s: &[u8], start: usize, pat: &[u8]) -> Option<usize> {
+    if start > s.len() {
+        return None;
+    }
+    s[start..]
+        .windows(pat.len())
+        .position(|w| w.eq_ignore_ascii_case(pat))
+        .map(|k| k + start)
+}
+
+/// Check whether s starts with tag name followed by white space, '/' or '>'.
+fn is_tag(s: &[u8], tag: &[u8]) -> bool {
+    let n = tag.len();
+    s.len() > n
+        && s[..n].eq_ignore_ascii_case(tag)
+        && (s[n] == b'>' || s[n] == b'/' || s[n].is_ascii_whitespace())
+}
+
+/// Get end of tag starting at i ( quoted attribute values may contain '>' ).
+fn tag_end(s: &[u8], mut i: usize) -> usize {
+    while i < s.len() {
+        match s[i] {
+            b'>' => return i + 1,
+            b'"' | b'\'' => i = quote_end(s, i, false),
+            _ => i += 1,
+        }
+    }
+    i
+}
+
+/// Get end of quoted string starting at i, escape is whether backslash escapes the next char.
+fn quote_end(s: &[u8], i: usize, escape: bool) -> usize {
+    let q = s[i];
+    let mut j = i + 1;
+    while j < s.len() {
+        if escape && s[j] == b'\\' {
+            j += 2;
+            continue;
+        }
+        if s[j] == q {
+            return j + 1;
+        }
+        j += 1;
+    }
+    s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn h(s: &str) -> String {
+        String::from_utf8(html(s.as_bytes())).unwrap()
+    }
+
+    fn c(s: &str) -> String {
+        String::from_utf8(css(s.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn html_white_space() {
+        assert_eq!(h("<p>  a   b </p>\n\n  <p>c</p>"), "<p> a b </p>\n<p>c</p>");
+        assert_eq!(h("<p>a<!-- note -->b</p>"), "<p>ab</p>");
+        assert_eq!(h("<!--[if IE]>x<![endif]-->"), "<!--[if IE]>x<![endif]-->");
+        assert_eq!(h("a<!-- unterminated"), "a");
+    }
+
+    #[test]
+    fn html_tags_unchanged() {
+        assert_eq!(
+            h("<a  title=\"x  > y\"  href='/'>"),
+            "<a  title=\"x  > y\"  href='/'>"
+        );
+        assert_eq!(h("a < b"), "a < b");
+    }
+
+    #[test]
+    fn html_raw_elements() {
+        let pre = "<pre>  a\n\n  b <!-- c --></pre>  x";
+        assert_eq!(h(pre), "<pre>  a\n\n  b <!-- c --></pre> x");
+        let script = "<SCRIPT>if (a  <  b) s = '  <!-- ';</Script>";
+        assert_eq!(h(script), script);
+        let style = "<style type=\"text/css\">p  {  }</style>";
+        assert_eq!(h(style), style);
+        // Not a raw element.
+        assert_eq!(h("<prefix>  a</prefix>"), "<prefix> a</prefix>");
+        // Unterminated raw element is copied to the end.
+        assert_eq!(h("<textarea>  a  "), "<textarea>  a  ");
+    }
+
+    #[test]
+    fn css_minify() {
+        assert_eq!(
+            c("p  {\n  color : red ;\n  margin: 0 auto;\n}\n"),
+            "p{color : red;margin: 0 auto;}"
+        );
+        assert_eq!(c("a , b /* note */ { }"), "a,b{}");
+        assert_eq!(c("a /* unterminated"), "a ");
+    }
+
+    #[test]
+    fn css_strings() {
+        assert_eq!(
+            c("p::before { content: \"  a ; /* b */ \" }"),
+            "p::before{content: \"  a ; /* b */ \"}"
+        );
+        assert_eq!(c("q { content: '\\'  ,  ' }"), "q{content: '\\'  ,  '}");
+        assert_eq!(c("q { content: \"  "), "q{content: \"  ");
+    }
+
+    #[test]
+    fn parse_spec() {
+        assert!(parse("text/HTML=html").is_ok_and(|(ct, _)| ct == "text/html"));
+        assert!(parse("text/html").is_err());
+        assert!(parse("text/html=js").is_err());
+    }
+}
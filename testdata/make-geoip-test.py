@@ -0,0 +1,59 @@
+# Make the MaxMind DB file used by the geoip tests: 1.2.3.0/24 is AU ( with more fields ), 2001:db8::/32 is DE.
+import struct, ipaddress, os
+def ctl(t, size):
+    out=b''
+    if size<29: s=size; ext=b''
+    elif size<285: s=29; ext=bytes([size-29])
+    else: s=30; ext=struct.pack('>H',size-285)
+    if t<=7: out=bytes([(t<<5)|s])
+    else: out=bytes([s, t-7])
+    return out+ext
+class U16(int): pass
+class U64(int): pass
+def enc(v, ptrs=None):
+    if isinstance(v,str): b=v.encode(); return ctl(2,len(b))+b
+    if isinstance(v,bool): return ctl(14,int(v))
+    if isinstance(v,int):
+        b=v.to_bytes((v.bit_length()+7)//8,'big') if v else b''
+        return ctl(5 if isinstance(v,U16) else 9 if isinstance(v,U64) or v>=2**32 else 6,len(b))+b
+    if isinstance(v,float): return ctl(3,8)+struct.pack('>d',v)
+    if isinstance(v,dict):
+        out=ctl(7,len(v))
+        for k,x in v.items():
+            if ptrs is not None and k in ptrs: out+=bytes([0x20|(ptrs[k]>>8),ptrs[k]&255])
+            else: out+=enc(k)
+            out+=enc(x,ptrs)
+        return out
+    if isinstance(v,list): return ctl(11,len(v))+b''.join(enc(x,ptrs) for x in v)
+data=bytearray(); ptrs={}
+# shared key string "iso_code" at data offset 0, referenced by pointer
+ptrs['iso_code']=len(data); data+=enc('iso_code')
+recs=[({'country':{'iso_code':'AU','names':{'en':'Australia'}},'subdivisions':[{'iso_code':'NSW'}],'n':{'big':123456789,'f':1.5,'b':True}}, '1.2.3.0/24'),
+      ({'country':{'iso_code':'DE'}}, '2001:db8::/32')]
+nets=[]
+for v,net in recs:
+    off=len(data); data+=enc(v,ptrs)
+    n=ipaddress.ip_network(net)
+    if n.version==4: bits=int(n.network_address)<<0; plen=96+n.prefixlen; val=int(n.network_address)
+    else: val=int(n.network_address); plen=n.prefixlen
+    nets.append((val,plen,off))
+# build trie
+nodes=[[None,None]]
+for val,plen,off in nets:
+    node=0
+    for i in range(plen):
+        bit=(val>>(127-i))&1
+        if i==plen-1: nodes[node][bit]=('d',off); break
+        if nodes[node][bit] is None: nodes.append([None,None]); nodes[node][bit]=len(nodes)-1
+        node=nodes[node][bit]
+nc=len(nodes)
+tree=bytearray()
+for l,r in nodes:
+    for x in (l,r):
+        if x is None: v=nc
+        elif isinstance(x,tuple): v=nc+16+x[1]
+        else: v=x
+        tree+=v.to_bytes(3,'big')
+meta={'node_count':nc,'record_size':U16(24),'ip_version':U16(6),'database_type':'Test','binary_format_major_version':U16(2),'binary_format_minor_version':U16(0),'build_epoch':U64(1),'languages':['en'],'description':{'en':'t'}}
+out=tree+bytes(16)+data+b'\xab\xcd\xefMaxMind.com'+enc(meta)
+open(os.path.join(os.path.dirname(os.path.abspath(__file__)), 'geoip-test.mmdb'),'wb').write(out)